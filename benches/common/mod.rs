@@ -0,0 +1,172 @@
+//! Helpers shared by the benchmarks: a client started on a temporary directory with a seeded
+//! segment cache, a fake drone that keeps the packet processing busy and a minimal http client.
+// every benchmark uses only part of the helpers
+#![allow(dead_code)]
+
+use client_audio::ClientAudio;
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender};
+use packet_forge::ClientT;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use wg_internal::controller::DroneCommand;
+use wg_internal::network::{NodeId, SourceRoutingHeader};
+use wg_internal::packet::{FloodRequest, NodeType, Packet};
+
+/// Id of the client under test
+pub const CLIENT_ID: NodeId = 10;
+/// Id of the only neighbour of the client
+pub const DRONE_ID: NodeId = 1;
+/// Id of the client that floods the network behind the drone
+pub const FLOODER_ID: NodeId = 50;
+/// Song whose segments are in the segment cache
+pub const CACHED_SONG: u16 = 1;
+/// Song that no peer has
+pub const MISSING_SONG: u16 = 2;
+
+/// A client running on its own thread, stopped with a crash command
+pub struct BenchClient {
+    pub port: u16,
+    dir: PathBuf,
+    command_send: Sender<DroneCommand>,
+    packet_send: Sender<Packet>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl BenchClient {
+    /// Start a client whose segment cache holds `segments` segments of `segment_size` bytes of `CACHED_SONG`,
+    /// reachable as `/audio/1/segment<N>.ts`
+    pub fn start(name: &str, port: u16, segments: u32, segment_size: usize) -> Self {
+        let dir = std::env::temp_dir().join(format!("client-audio-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let db_path = dir.join("db");
+
+        fs::write(dir.join("songs.json"), r#"{"songs": []}"#).unwrap();
+        let config = serde_json::json!({
+            "http_port": port,
+            "db_path": db_path.to_str().unwrap(),
+        });
+        fs::write(dir.join("client_config.json"), config.to_string()).unwrap();
+
+        // the segment cache is kept between runs, fill it before the client opens the database
+        let db = sled::open(&db_path).unwrap();
+        let cache = db.open_tree("segment_cache").unwrap();
+        for segment in 0..segments {
+            // the playlist is stored at index 0, so segment N is stored at index N + 1
+            let mut key = (segment + 1).to_be_bytes().to_vec();
+            key.extend_from_slice(&CACHED_SONG.to_be_bytes());
+            cache.insert(key, vec![0u8; segment_size]).unwrap();
+        }
+        db.flush().unwrap();
+        drop(cache);
+        drop(db);
+
+        let (event_send, event_recv) = unbounded();
+        let (command_send, command_recv) = unbounded();
+        let (packet_send, packet_recv) = bounded(1024);
+        let (drone_send, drone_recv) = unbounded();
+        drain(event_recv);
+        drain(drone_recv);
+
+        let senders = HashMap::from([(DRONE_ID, drone_send)]);
+        let client =
+            <ClientAudio as ClientT>::new(CLIENT_ID, event_send, command_recv, packet_recv, senders);
+        let path = dir.to_str().unwrap().to_string();
+        let handle = thread::spawn(move || Box::new(client).run(&path));
+
+        let bench_client = BenchClient {
+            port,
+            dir,
+            command_send,
+            packet_send,
+            handle: Some(handle),
+        };
+        bench_client.wait_ready();
+        bench_client
+    }
+
+    /// Wait for rocket to accept connections
+    fn wait_ready(&self) {
+        let start = Instant::now();
+        while TcpStream::connect(("127.0.0.1", self.port)).is_err() {
+            assert!(
+                start.elapsed() < Duration::from_secs(30),
+                "the client did not start"
+            );
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// Channel on which the client receives the packets of its neighbour
+    pub fn packet_sender(&self) -> Sender<Packet> {
+        self.packet_send.clone()
+    }
+
+    /// Crash the client and wait for it to terminate
+    pub fn stop(mut self) {
+        let _ = self.command_send.send(DroneCommand::Crash);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Throw away everything the client sends until it drops the channel
+fn drain<T: Send + 'static>(receiver: Receiver<T>) {
+    thread::spawn(move || while receiver.recv().is_ok() {});
+}
+
+/// Keep the packet processing busy: send flood requests started behind the drone, each one is
+/// answered with a flood response, until `stop` is set. Returns the number of packets sent.
+pub fn flood_load(packet_send: Sender<Packet>, stop: Arc<AtomicBool>) -> JoinHandle<u64> {
+    thread::spawn(move || {
+        let mut flood_id = 0;
+        while !stop.load(Ordering::Relaxed) {
+            flood_id += 1;
+            let flood_req = FloodRequest {
+                flood_id,
+                initiator_id: FLOODER_ID,
+                path_trace: vec![(FLOODER_ID, NodeType::Client), (DRONE_ID, NodeType::Drone)],
+            };
+            let packet =
+                Packet::new_flood_request(SourceRoutingHeader::new(vec![], 0), flood_id, flood_req);
+            if packet_send.send(packet).is_err() {
+                break;
+            }
+        }
+        flood_id
+    })
+}
+
+/// Send a GET request and return the status code and the length of the body
+pub fn get(port: u16, path: &str) -> (u16, usize) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        path
+    );
+    stream.write_all(request.as_bytes()).unwrap();
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+
+    let status = response
+        .split(|b| *b == b' ')
+        .nth(1)
+        .and_then(|code| std::str::from_utf8(code).ok())
+        .and_then(|code| code.parse().ok())
+        .unwrap_or(0);
+    let body = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map_or(0, |end| response.len() - end - 4);
+    (status, body)
+}
@@ -0,0 +1,92 @@
+//! Throughput of the `/audio` endpoint under concurrent segment requests, with the packet processing
+//! idle and kept busy by a flood of packets. The segments served from the cache and the cache misses
+//! answered by the processing thread should keep the same throughput under load, as the endpoint
+//! never waits for the client state lock.
+//!
+//! Run with `cargo bench --bench concurrent_segments`.
+
+mod common;
+
+use common::{BenchClient, CACHED_SONG, MISSING_SONG};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const PORT: u16 = 18_010;
+const SEGMENTS: u32 = 64;
+const SEGMENT_SIZE: usize = 64 * 1024;
+const REQUESTS_PER_WORKER: usize = 200;
+const WORKERS: [usize; 3] = [1, 8, 32];
+
+fn main() {
+    let client = BenchClient::start("concurrent-segments", PORT, SEGMENTS, SEGMENT_SIZE);
+
+    println!(
+        "{:<8} {:<6} {:>8} {:>12} {:>10} {:>10}",
+        "segment", "load", "workers", "req/s", "p50", "p99"
+    );
+    for loaded in [false, true] {
+        let stop = Arc::new(AtomicBool::new(false));
+        let load = loaded.then(|| common::flood_load(client.packet_sender(), stop.clone()));
+
+        for workers in WORKERS {
+            // the cached segments are answered with 200, the missing song with 404 by the processing thread
+            run("hit", loaded, workers, |worker, i| {
+                let segment = (worker + i) as u32 % SEGMENTS;
+                (format!("/audio/{}/segment{}.ts", CACHED_SONG, segment), 200)
+            });
+            run("miss", loaded, workers, |worker, i| {
+                let segment = (worker * REQUESTS_PER_WORKER + i) as u32;
+                (format!("/audio/{}/segment{}.ts", MISSING_SONG, segment), 404)
+            });
+        }
+
+        stop.store(true, Ordering::Relaxed);
+        if let Some(load) = load {
+            let packets = load.join().unwrap();
+            println!("{} flood requests processed during the loaded runs", packets);
+        }
+    }
+
+    client.stop();
+}
+
+/// Send `REQUESTS_PER_WORKER` requests from each of `workers` threads and print the throughput
+/// and the latency percentiles
+fn run(name: &str, loaded: bool, workers: usize, request: fn(usize, usize) -> (String, u16)) {
+    let start = Instant::now();
+    let handles: Vec<_> = (0..workers)
+        .map(|worker| {
+            thread::spawn(move || {
+                let mut latencies = Vec::with_capacity(REQUESTS_PER_WORKER);
+                for i in 0..REQUESTS_PER_WORKER {
+                    let (path, expected) = request(worker, i);
+                    let sent = Instant::now();
+                    let (status, _) = common::get(PORT, &path);
+                    latencies.push(sent.elapsed());
+                    assert_eq!(status, expected, "unexpected status for {}", path);
+                }
+                latencies
+            })
+        })
+        .collect();
+
+    let mut latencies: Vec<Duration> = handles
+        .into_iter()
+        .flat_map(|handle| handle.join().unwrap())
+        .collect();
+    let elapsed = start.elapsed();
+    latencies.sort();
+
+    let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+    println!(
+        "{:<8} {:<6} {:>8} {:>12.0} {:>10.2?} {:>10.2?}",
+        name,
+        if loaded { "flood" } else { "idle" },
+        workers,
+        latencies.len() as f64 / elapsed.as_secs_f64(),
+        percentile(50),
+        percentile(99)
+    );
+}
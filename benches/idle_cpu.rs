@@ -0,0 +1,67 @@
+//! CPU time used by an idle client: no packets, commands or http requests arrive, so the
+//! message loop should sleep until the next scheduled flood instead of waking up periodically.
+//! The process-wide CPU time and the voluntary context switches of its threads are read from `/proc`,
+//! so the benchmark runs on Linux only.
+//!
+//! Run with `cargo bench --bench idle_cpu`.
+
+mod common;
+
+use common::BenchClient;
+use std::fs;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const PORT: u16 = 18_012;
+const WARMUP: Duration = Duration::from_secs(2);
+const IDLE: Duration = Duration::from_secs(10);
+/// `sysconf(_SC_CLK_TCK)`, the unit of the times in `/proc/self/stat`, is 100 on every Linux platform
+const CLOCK_TICKS_PER_SECOND: f64 = 100.0;
+
+fn main() {
+    let client = BenchClient::start("idle-cpu", PORT, 0, 0);
+    thread::sleep(WARMUP);
+
+    let start = Instant::now();
+    let (cpu_start, switches_start) = (cpu_time(), context_switches());
+    thread::sleep(IDLE);
+    let (cpu_end, switches_end) = (cpu_time(), context_switches());
+    let elapsed = start.elapsed();
+
+    let cpu = cpu_end - cpu_start;
+    println!(
+        "idle for {:.1?}: {:.1?} of cpu time ({:.3} % of a core), {} context switches per second",
+        elapsed,
+        cpu,
+        cpu.as_secs_f64() / elapsed.as_secs_f64() * 100.0,
+        (switches_end - switches_start) as f64 / elapsed.as_secs_f64()
+    );
+
+    client.stop();
+}
+
+/// User and system time used by the process
+fn cpu_time() -> Duration {
+    let stat = fs::read_to_string("/proc/self/stat").unwrap();
+    // the fields after the command name, which is in parentheses and may contain spaces
+    let fields: Vec<&str> = stat[stat.rfind(')').unwrap() + 2..].split(' ').collect();
+    // utime and stime are the 14th and 15th fields of the whole line
+    let ticks: u64 = fields[11].parse::<u64>().unwrap() + fields[12].parse::<u64>().unwrap();
+    Duration::from_secs_f64(ticks as f64 / CLOCK_TICKS_PER_SECOND)
+}
+
+/// Voluntary context switches of every thread of the process, one for each time a thread sleeps
+fn context_switches() -> u64 {
+    fs::read_dir("/proc/self/task")
+        .unwrap()
+        .filter_map(Result::ok)
+        .filter_map(|task| fs::read_to_string(task.path().join("status")).ok())
+        .filter_map(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("voluntary_ctxt_switches:"))
+                .and_then(|count| count.trim().parse::<u64>().ok())
+        })
+        .sum()
+}
+
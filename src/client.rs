@@ -1,23 +1,63 @@
-use crate::client_endpoints::{audio_files, get_id, get_song, is_ready};
-use crate::database::AudioDatabase;
-use crossbeam::channel::{Receiver, Sender};
+use crate::client_endpoints::{
+    audio_files, get_id, get_song, history_stats, is_ready, network_graph, network_neighbours,
+    network_paths, network_servers, network_stats, pending_requests, progress,
+};
+use crate::config::ClientConfig;
+use crate::database::{AudioDatabase, DEFAULT_CACHE_BUDGET};
+use crate::error::{ClientError, ClientResult};
+use downloads::{DownloadScheduler, DEFAULT_PREFETCH_WINDOW};
+use flood_scheduler::FloodScheduler;
+use inflight::InflightRequests;
+use peer_lists::PeerListRequests;
+use reassembly::ReassemblyBuffer;
+use requested_segments::RequestedSegments;
+use shared_logger::SharedLogger;
+use snapshot::StateSnapshot;
+use task::ClientTask;
+use topology::Topology;
+use packet_history::{PacketHistory, DEFAULT_HISTORY_MAX_AGE_SECS, DEFAULT_HISTORY_MAX_BYTES};
+use progress::PROGRESS_CHANNEL_CAPACITY;
+use crossbeam::channel::{unbounded, Receiver, Sender};
 use logger::{LogLevel, Logger};
 use packet_forge::ClientT;
-use packet_forge::{FileHash, PacketForge, SessionIdT};
+use packet_forge::{FileHash, PacketForge};
 use rocket::fs::relative;
 use rocket::{Build, Config, Rocket};
 use routing_handler::RoutingHandler;
-use std::collections::HashMap;
-use std::sync::{Arc, LazyLock, RwLock};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, LazyLock, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::broadcast;
 use wg_internal::controller::{DroneCommand, DroneEvent};
 use wg_internal::network::NodeId;
-use wg_internal::packet::{Fragment, Packet};
+use wg_internal::packet::Packet;
+pub(crate) mod downloads;
+mod flood_scheduler;
+mod inflight;
 mod message_handler;
+mod network_report;
+pub(crate) mod packet_history;
+mod peer_lists;
+mod progress;
+mod reassembly;
+mod report;
+mod requested_segments;
+mod shared_logger;
+mod snapshot;
+pub(crate) mod task;
+mod topology;
+
+pub use network_report::{NetworkGraph, NetworkNode, NetworkPath, NetworkReport, NetworkServers};
+pub use packet_history::HistoryStats;
+pub use progress::ProgressEvent;
+pub use report::{CacheStats, ClientReport, DownloadsReport};
+pub use topology::NodeStats;
 
 static RT: LazyLock<tokio::runtime::Runtime> =
     LazyLock::new(|| tokio::runtime::Runtime::new().unwrap());
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 pub enum Status {
     Starting,
     Idle,
@@ -25,29 +65,85 @@ pub enum Status {
     Terminated,
 }
 
+/// State of the message processing thread.
+/// The components that the rocket endpoints read (database, downloads, in-flight requests, status, settings, logger and snapshot)
+/// are synchronized on their own and shared with `ClientAudio`, so reading them never waits for the packet processing.
+/// The endpoints never take the state lock: the work that needs it is posted to the processing thread as a `ClientTask`.
 pub struct ClientState {
     pub id: NodeId,
     pub flood_id: u64,
+    pub flood_scheduler: FloodScheduler,
     pub servers_id: Vec<NodeId>,
+    pub subscribed_servers: HashSet<NodeId>,
+    pub announced_songs: HashSet<FileHash>,
     pub controller_send: Sender<DroneEvent>,
     pub controller_recv: Receiver<DroneCommand>,
     pub packet_recv: Receiver<Packet>,
+    pub task_recv: Receiver<ClientTask>,
     pub senders: HashMap<NodeId, Sender<Packet>>,
-    pub inner_senders: HashMap<(FileHash, u32), Sender<bool>>,
+    pub inflight: Arc<Mutex<InflightRequests>>,
     pub packet_forge: PacketForge,
-    pub status: Status,
-    pub db: AudioDatabase,
-    pub logger: Logger,
+    pub status: Arc<RwLock<Status>>,
+    pub db: Arc<AudioDatabase>,
+    pub logger: SharedLogger,
     pub routing_handler: RoutingHandler,
-    pub packets_map: HashMap<(NodeId, SessionIdT), Vec<Fragment>>,
-    pub client_song_map: HashMap<FileHash, NodeId>,
-    pub song_map: HashMap<(FileHash, u32), Vec<u8>>,
-    pub packets_history: HashMap<(u64, SessionIdT), Packet>,
+    pub topology: Topology,
+    pub reassembly: ReassemblyBuffer,
+    pub client_song_map: HashMap<FileHash, Vec<NodeId>>,
+    pub peer_lists: PeerListRequests,
+    pub requested_segments: RequestedSegments,
+    pub packets_history: PacketHistory,
+    pub downloads: Arc<Mutex<DownloadScheduler>>,
+    pub config: Arc<ClientConfig>,
+    // dropped on shutdown to wake up the threads waiting on `ClientAudio::shutdown_recv`
+    pub shutdown_send: Option<Sender<()>>,
+    pub progress: broadcast::Sender<ProgressEvent>,
+    pub snapshot: Arc<RwLock<StateSnapshot>>,
+}
+
+impl ClientState {
+    pub fn status(&self) -> Status {
+        *self.status.read().unwrap()
+    }
+
+    pub fn set_status(&self, status: Status) {
+        *self.status.write().unwrap() = status;
+    }
+
+    /// Send a progress event to the frontends listening on the `/progress` endpoint
+    pub fn emit_progress(&self, event: ProgressEvent) {
+        // no frontend is listening
+        let _ = self.progress.send(event);
+    }
+
+    /// Publish the parts of the state read without the state lock
+    pub fn publish_snapshot(&self) {
+        let mut subscribed_servers: Vec<NodeId> = self.subscribed_servers.iter().copied().collect();
+        subscribed_servers.sort_unstable();
+
+        let snapshot = StateSnapshot {
+            servers: self.servers_id.clone(),
+            subscribed_servers,
+            history: self.packets_history.stats(),
+        };
+        *self.snapshot.write().unwrap() = snapshot;
+    }
 }
 
 #[derive(Clone)]
 pub struct ClientAudio {
+    pub id: NodeId,
     pub state: Arc<RwLock<ClientState>>,
+    pub(crate) db: Arc<AudioDatabase>,
+    pub(crate) downloads: Arc<Mutex<DownloadScheduler>>,
+    pub(crate) inflight: Arc<Mutex<InflightRequests>>,
+    pub(crate) status: Arc<RwLock<Status>>,
+    pub(crate) config: Arc<ClientConfig>,
+    pub(crate) shutdown_recv: Receiver<()>,
+    pub(crate) progress: broadcast::Sender<ProgressEvent>,
+    pub(crate) logger: SharedLogger,
+    pub(crate) tasks: Sender<ClientTask>,
+    pub(crate) snapshot: Arc<RwLock<StateSnapshot>>,
 }
 
 impl ClientT for ClientAudio {
@@ -74,47 +170,27 @@ impl ClientT for ClientAudio {
     }
 
     fn with_info(&self) {
-        self.state
-            .write()
-            .unwrap()
-            .logger
-            .add_displayable_flag(LogLevel::Info);
+        self.logger.add_displayable_flag(LogLevel::Info);
     }
 
     fn with_debug(&self) {
-        self.state
-            .write()
-            .unwrap()
-            .logger
-            .add_displayable_flag(LogLevel::Debug);
+        self.logger.add_displayable_flag(LogLevel::Debug);
     }
 
     fn with_error(&self) {
-        self.state
-            .write()
-            .unwrap()
-            .logger
-            .add_displayable_flag(LogLevel::Error);
+        self.logger.add_displayable_flag(LogLevel::Error);
     }
 
     fn with_warning(&self) {
-        self.state
-            .write()
-            .unwrap()
-            .logger
-            .add_displayable_flag(LogLevel::Warn);
+        self.logger.add_displayable_flag(LogLevel::Warn);
     }
 
     fn with_all(&self) {
-        self.state
-            .write()
-            .unwrap()
-            .logger
-            .add_displayable_flag(LogLevel::All);
+        self.logger.add_displayable_flag(LogLevel::All);
     }
 
     fn with_web_socket(&self) {
-        self.state.write().unwrap().logger.init_web_socket();
+        self.logger.init_web_socket();
     }
 }
 
@@ -126,95 +202,221 @@ impl ClientAudio {
         receiver: Receiver<Packet>,
         senders: HashMap<NodeId, Sender<Packet>>,
     ) -> Self {
-        let db_path = &format!("db/client_audio/client-{}", id);
+        let db = Arc::new(AudioDatabase::new(DEFAULT_CACHE_BUDGET));
+        let downloads = Arc::new(Mutex::new(DownloadScheduler::new(DEFAULT_PREFETCH_WINDOW)));
+        let inflight = Arc::new(Mutex::new(InflightRequests::default()));
+        let status = Arc::new(RwLock::new(Status::Starting));
+        let config = Arc::new(ClientConfig::default());
+        let (shutdown_send, shutdown_recv) = unbounded();
+        let (progress, _) = broadcast::channel(PROGRESS_CHANNEL_CAPACITY);
+        let (tasks, task_recv) = unbounded();
+        let snapshot = Arc::new(RwLock::new(StateSnapshot::default()));
+        let logger = SharedLogger::new(Logger::new(
+            LogLevel::None as u8,
+            false,
+            format!("audio_client_{}", id),
+        ));
+
         let state = ClientState {
             id,
             flood_id: 0,
+            flood_scheduler: FloodScheduler::new(Duration::from_secs(config.flood_refresh_secs)),
             servers_id: Vec::new(),
+            subscribed_servers: HashSet::new(),
+            announced_songs: HashSet::new(),
             controller_send: command_send,
             controller_recv: command_recv,
             packet_recv: receiver,
+            task_recv,
             senders,
-            inner_senders: HashMap::new(),
+            inflight: inflight.clone(),
             packet_forge: PacketForge::new(),
-            status: Status::Starting,
-            db: AudioDatabase::new(db_path),
-            logger: Logger::new(LogLevel::None as u8, false, format!("audio_client_{}", id)),
+            status: status.clone(),
+            db: db.clone(),
+            logger: logger.clone(),
             routing_handler: RoutingHandler::new(),
-            packets_map: HashMap::new(),
-            packets_history: HashMap::new(),
-            song_map: HashMap::new(),
+            topology: Topology::default(),
+            reassembly: ReassemblyBuffer::default(),
+            packets_history: PacketHistory::new(
+                Duration::from_secs(DEFAULT_HISTORY_MAX_AGE_SECS),
+                DEFAULT_HISTORY_MAX_BYTES,
+            ),
             client_song_map: HashMap::new(),
+            peer_lists: PeerListRequests::default(),
+            requested_segments: RequestedSegments::default(),
+            downloads: downloads.clone(),
+            config: config.clone(),
+            shutdown_send: Some(shutdown_send),
+            progress: progress.clone(),
+            snapshot: snapshot.clone(),
         };
 
         ClientAudio {
+            id,
             state: Arc::new(RwLock::new(state)),
+            db,
+            downloads,
+            inflight,
+            status,
+            config,
+            shutdown_recv,
+            progress,
+            logger,
+            tasks,
+            snapshot,
         }
     }
 
     #[must_use]
     fn configure(client: ClientAudio) -> Rocket<Build> {
         // Config rocket to use a different port for each client
+        let port = client.config.http_port(client.id);
         let config = Config {
-            port: 8000 + u16::from(client.get_id()),
+            port,
             ..Config::default()
         };
 
         rocket::custom(&config)
             .manage(client)
-            .mount("/", routes![audio_files, get_song, is_ready, get_id])
+            .mount(
+                "/",
+                routes![
+                    audio_files,
+                    get_song,
+                    is_ready,
+                    get_id,
+                    pending_requests,
+                    history_stats,
+                    network_graph,
+                    network_servers,
+                    network_neighbours,
+                    network_paths,
+                    network_stats,
+                    progress
+                ],
+            )
             .mount("/", rocket::fs::FileServer::from(relative!("static")))
     }
 
     /// Start the thread that processes incoming messages and the rocket endpoints
     ///
     /// init_client_path: path to the client's database
-    async fn run_internal(self, init_client_path: &str) {
+    async fn run_internal(mut self, init_client_path: &str) {
+        // Load the settings file next to the client's files
+        let config = match ClientConfig::load(init_client_path) {
+            Ok(config) => config,
+            Err(e) => {
+                self.logger.log_error(&e.to_string());
+                ClientConfig::default()
+            }
+        };
+        self.apply_config(config);
+
+        // Open the database only now that its path is known
+        let db_path = self.config.db_path(self.id);
+        if let Err(e) = self.db.open(&db_path) {
+            let mut state = self.state.write().unwrap();
+            state.logger.log_error(&e.to_string());
+            Self::shutdown(&mut state);
+            return;
+        }
+
         // Initialize the database
-        match self.state.read().unwrap().db.init(init_client_path) {
-            Ok(_) => self
-                .state
-                .read()
-                .unwrap()
-                .logger
-                .log_info("Database initialized"),
-            Err(e) => self.state.read().unwrap().logger.log_error(e.as_str()),
+        match self.db.init(init_client_path) {
+            Ok(_) => self.logger.log_info("Database initialized"),
+            Err(e) => self.logger.log_error(&e.to_string()),
         }
 
         let processing_handle = self.clone().start_message_processing();
-        let state = self.state.clone();
+        let client = self.clone();
 
-        // Launch rocket in a separate task
-        let rocket = Self::configure(self).launch();
+        let rocket = match Self::configure(self).ignite().await {
+            Ok(rocket) => rocket,
+            Err(e) => {
+                client
+                    .logger
+                    .log_error(&format!("Error starting rocket: {}", e));
+                Self::shutdown(&mut client.state.write().unwrap());
+                let _ = tokio::task::spawn_blocking(move || processing_handle.join()).await;
+                return;
+            }
+        };
 
-        // Monitor termination flag in a separate task
+        // When the processing thread terminates, tell rocket to stop serving the frontend
+        let shutdown = rocket.shutdown();
+        let processing_handle = tokio::task::spawn_blocking(move || processing_handle.join());
         let termination_handle = tokio::spawn(async move {
-            loop {
-                if state.read().unwrap().status == Status::Terminated {
-                    // Wait for processing thread to complete
-                    let _ = processing_handle.join();
-                    break;
-                }
-                tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-            }
+            let _ = processing_handle.await;
+            shutdown.notify();
         });
 
-        // Run both tasks concurrently
-        tokio::select! {
-            _ = rocket => {},
-            _ = termination_handle => {},
+        if let Err(e) = rocket.launch().await {
+            client
+                .logger
+                .log_error(&format!("Error running rocket: {}", e));
         }
-        
+
+        // Rocket may also stop on its own (e.g. ctrl-c), stop the processing thread as well
+        Self::shutdown(&mut client.state.write().unwrap());
+        let _ = termination_handle.await;
+
         println!("[CLIENT] Terminated");
     }
 
-    fn get_id(&self) -> NodeId {
-        match self.state.read() {
-            Ok(state) => state.id,
-            Err(e) => {
-                eprintln!("Error reading state {}", e);
-                0
-            }
+    /// Apply the settings to the client state, before the database is opened
+    fn apply_config(&mut self, config: ClientConfig) {
+        let mut state = self.state.write().unwrap();
+
+        self.db.set_cache_budget(config.cache_budget_bytes);
+
+        self.downloads
+            .lock()
+            .unwrap()
+            .set_prefetch_window(config.prefetch_window);
+
+        state
+            .flood_scheduler
+            .set_base_interval(Duration::from_secs(config.flood_refresh_secs));
+        state.packets_history.set_limits(
+            Duration::from_secs(config.history_max_age_secs),
+            config.history_max_bytes,
+        );
+
+        state.logger.log_info(&format!("Client configured: {:?}", config));
+        self.config = Arc::new(config);
+        state.config = self.config.clone();
+    }
+
+    /// Hand a task to the message processing thread.
+    /// Fails with `Terminated` if the client has shut down and nobody would handle it.
+    pub(crate) fn post(&self, task: ClientTask) -> ClientResult<()> {
+        if self.status() == Status::Terminated {
+            return Err(ClientError::Terminated);
         }
+        self.tasks.send(task).map_err(|_| ClientError::Terminated)
+    }
+
+    /// Called by the rocket endpoint after a segment has been requested by the browser:
+    /// request in background the next segments of the read-ahead window
+    pub(crate) fn prefetch(&self, file_id: FileHash, segment: u32) {
+        // nothing to prefetch, do not wake up the packet processing
+        if self.downloads.lock().unwrap().window(file_id, segment).is_empty() {
+            return;
+        }
+        // the client is shutting down, there is nothing to prefetch for
+        let _ = self.post(ClientTask::Prefetch { file_id, segment });
+    }
+
+    fn get_id(&self) -> NodeId {
+        self.id
+    }
+
+    /// Counters of the fragments waiting for an ack as of the last wakeup of the processing thread, for debugging
+    pub fn history_stats(&self) -> HistoryStats {
+        self.snapshot.read().unwrap().history.clone()
+    }
+
+    pub fn status(&self) -> Status {
+        *self.status.read().unwrap()
     }
 }
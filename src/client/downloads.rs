@@ -0,0 +1,93 @@
+use packet_forge::FileHash;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
+/// Number of consecutive segments requested to the same peer in a single `ChunkRequest`
+pub const STRIPE_SIZE: u32 = 4;
+/// Maximum number of segments answered for a single `ChunkRequest` of a peer
+pub const MAX_SEGMENTS_PER_REQUEST: usize = 64;
+/// Default number of segments requested ahead of the one played by the browser
+pub const DEFAULT_PREFETCH_WINDOW: u32 = 5;
+/// Largest read-ahead window accepted in the settings
+pub const MAX_PREFETCH_WINDOW: u32 = 64;
+
+/// Keeps track of the segments that have been requested in background to the peers,
+/// so that the rocket endpoint waits for them instead of sending a new request.
+/// The segments received in background are stored in the segment cache of the database,
+/// so the read-ahead is bounded by the cache budget.
+pub struct DownloadScheduler {
+    pending: HashSet<(FileHash, u32)>,
+    segment_count: HashMap<FileHash, u32>,
+    prefetch_window: u32,
+}
+
+impl DownloadScheduler {
+    pub fn new(prefetch_window: u32) -> Self {
+        DownloadScheduler {
+            pending: HashSet::new(),
+            segment_count: HashMap::new(),
+            prefetch_window,
+        }
+    }
+
+    /// Split the missing segments in ranges of at most `STRIPE_SIZE` consecutive segments.
+    /// The i-th range is meant to be requested to the i-th peer (round robin).
+    pub fn plan(missing: &[u32]) -> Vec<Range<u32>> {
+        let mut stripes: Vec<Range<u32>> = Vec::new();
+        for &segment in missing {
+            match stripes.last_mut() {
+                Some(last) if last.end == segment && last.end - last.start < STRIPE_SIZE => {
+                    last.end += 1;
+                }
+                _ => stripes.push(segment..segment + 1),
+            }
+        }
+        stripes
+    }
+
+    /// Segments that should be requested after `segment` has been played, according to the read-ahead window.
+    /// Returns an empty range if the playlist of the file has not been received yet.
+    pub fn window(&self, file_id: FileHash, segment: u32) -> Range<u32> {
+        let Some(count) = self.segment_count.get(&file_id) else {
+            return 0..0;
+        };
+        let start = segment.saturating_add(1);
+        let end = start
+            .saturating_add(self.prefetch_window)
+            .min(count.saturating_add(1));
+        start..end.max(start)
+    }
+
+    /// Save the number of segments of the file, parsed from its playlist
+    pub fn set_segment_count(&mut self, file_id: FileHash, count: u32) {
+        self.segment_count.insert(file_id, count);
+    }
+
+    pub fn set_prefetch_window(&mut self, prefetch_window: u32) {
+        self.prefetch_window = prefetch_window;
+    }
+
+    /// Mark all the segments of the range as requested
+    pub fn mark_pending(&mut self, file_id: FileHash, range: Range<u32>) {
+        for segment in range {
+            self.pending.insert((file_id, segment));
+        }
+    }
+
+    /// Check if the segment has already been requested in background
+    pub fn is_pending(&self, file_id: FileHash, segment: u32) -> bool {
+        self.pending.contains(&(file_id, segment))
+    }
+
+    /// Remove the segment from the pending ones, either because it was received or because the request failed
+    pub fn complete(&mut self, file_id: FileHash, segment: u32) {
+        self.pending.remove(&(file_id, segment));
+    }
+
+    /// Segments requested in background and not received yet, sorted by file and segment
+    pub fn pending_segments(&self) -> Vec<(FileHash, u32)> {
+        let mut pending: Vec<(FileHash, u32)> = self.pending.iter().copied().collect();
+        pending.sort_unstable();
+        pending
+    }
+}
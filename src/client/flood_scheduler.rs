@@ -0,0 +1,107 @@
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+use wg_internal::network::NodeId;
+use wg_internal::packet::NodeType;
+
+/// Minimum time between two flood requests, triggers received in the meantime are coalesced
+pub const MIN_FLOOD_INTERVAL: Duration = Duration::from_secs(2);
+/// The periodic flood interval grows up to this multiple of the configured one while the topology is stable
+pub const MAX_INTERVAL_FACTOR: u32 = 4;
+
+/// Decides when to send a flood request.
+/// Floods requested by nacks and topology commands are coalesced and rate limited, and the
+/// periodic flood is sent less often while the flood responses show no change in the topology.
+pub struct FloodScheduler {
+    base_interval: Duration,
+    interval: Duration,
+    last_flood: Option<Instant>,
+    requested: bool,
+    // the topology changed since the last flood
+    churn: bool,
+    known_edges: HashSet<(NodeId, NodeId)>,
+}
+
+impl FloodScheduler {
+    pub fn new(base_interval: Duration) -> Self {
+        FloodScheduler {
+            base_interval,
+            interval: base_interval,
+            last_flood: None,
+            requested: false,
+            // nothing is known about the topology yet
+            churn: true,
+            known_edges: HashSet::new(),
+        }
+    }
+
+    /// Change the periodic flood interval used while the topology changes
+    pub fn set_base_interval(&mut self, base_interval: Duration) {
+        self.base_interval = base_interval;
+        self.interval = base_interval;
+    }
+
+    /// Ask for a flood because the topology changed, it is sent by the next `poll` allowed by the rate limit
+    pub fn request(&mut self) {
+        self.requested = true;
+        self.churn = true;
+    }
+
+    /// Save the edges of a flood response, a new edge counts as a change of the topology
+    pub fn observe(&mut self, path_trace: &[(NodeId, NodeType)]) {
+        for pair in path_trace.windows(2) {
+            let (a, b) = (pair[0].0, pair[1].0);
+            if self.known_edges.insert((a.min(b), a.max(b))) {
+                self.churn = true;
+            }
+        }
+    }
+
+    /// Forget the edges removed from the routing graph, a known edge that disappears counts as a change of the topology
+    /// and is counted again if a flood response shows it back
+    pub fn removed(&mut self, edges: &[(NodeId, NodeId)]) {
+        for (a, b) in edges {
+            if self.known_edges.remove(&(*a.min(b), *a.max(b))) {
+                self.churn = true;
+            }
+        }
+    }
+
+    /// Check if a flood has to be sent now, either because it was requested or because the periodic interval elapsed
+    pub fn poll(&self, now: Instant) -> bool {
+        let Some(last_flood) = self.last_flood else {
+            return true;
+        };
+        let elapsed = now.duration_since(last_flood);
+        (self.requested && elapsed >= MIN_FLOOD_INTERVAL) || elapsed >= self.interval
+    }
+
+    /// Time at which `poll` will allow the next flood
+    pub fn next_flood(&self, now: Instant) -> Instant {
+        let Some(last_flood) = self.last_flood else {
+            return now;
+        };
+        if self.requested {
+            last_flood + MIN_FLOOD_INTERVAL.min(self.interval)
+        } else {
+            last_flood + self.interval
+        }
+    }
+
+    /// Record a sent flood and adapt the periodic interval: it is reset when the topology changed
+    /// since the previous flood, otherwise it is doubled up to `MAX_INTERVAL_FACTOR` times the configured one.
+    pub fn flooded(&mut self, now: Instant) {
+        if self.churn {
+            self.interval = self.base_interval;
+        } else {
+            self.interval = (self.interval * 2).min(self.base_interval * MAX_INTERVAL_FACTOR);
+        }
+        self.last_flood = Some(now);
+        self.requested = false;
+        self.churn = false;
+    }
+
+    /// Current interval between two periodic floods
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+}
@@ -0,0 +1,89 @@
+use crate::error::{ClientError, ClientResult};
+use packet_forge::FileHash;
+use std::collections::HashMap;
+use tokio::sync::oneshot::{self, Receiver, Sender};
+
+/// Segment of a song, the playlist is segment 0
+pub type SegmentKey = (FileHash, u32);
+
+/// Registry of the segments requested by the rocket endpoints and not yet answered by the network.
+/// Several endpoints can wait for the same segment: only the first one sends the network request
+/// and retries it, all of them receive the result.
+///
+/// The results are delivered through oneshot channels, so the endpoints can `await` them
+/// without blocking a thread of the rocket runtime.
+#[derive(Default)]
+pub struct InflightRequests {
+    next_waiter: u64,
+    waiters: HashMap<SegmentKey, Vec<(u64, Sender<ClientResult<Vec<u8>>>)>>,
+}
+
+impl InflightRequests {
+    /// Register a waiter for the segment.
+    /// Returns the waiter id, the channel on which the result is received and
+    /// whether the waiter is the first one, i.e. it has to send the network request.
+    pub fn register(&mut self, key: SegmentKey) -> (u64, Receiver<ClientResult<Vec<u8>>>, bool) {
+        let (sender, receiver) = oneshot::channel();
+        let waiter_id = self.next_waiter;
+        self.next_waiter += 1;
+
+        let waiters = self.waiters.entry(key).or_default();
+        let first = waiters.is_empty();
+        waiters.push((waiter_id, sender));
+        (waiter_id, receiver, first)
+    }
+
+    /// Send the result to every waiter of the segment and remove them.
+    /// Returns the number of waiters that have been notified.
+    pub fn complete(&mut self, key: SegmentKey, result: ClientResult<Vec<u8>>) -> usize {
+        let Some(waiters) = self.waiters.remove(&key) else {
+            return 0;
+        };
+        let notified = waiters.len();
+        for (_, sender) in waiters {
+            // the waiter may have given up in the meantime
+            let _ = sender.send(result.clone());
+        }
+        notified
+    }
+
+    /// Send the error to every waiter of every segment, used when the client shuts down.
+    /// Returns the number of waiters that have been notified.
+    pub fn fail_all(&mut self, error: ClientError) -> usize {
+        let keys: Vec<SegmentKey> = self.waiters.keys().copied().collect();
+        keys.into_iter()
+            .map(|key| self.complete(key, Err(error.clone())))
+            .sum()
+    }
+
+    /// Remove a waiter that stopped waiting, e.g. after a timeout
+    pub fn cancel(&mut self, key: SegmentKey, waiter_id: u64) {
+        if let Some(waiters) = self.waiters.get_mut(&key) {
+            waiters.retain(|(id, _)| *id != waiter_id);
+            if waiters.is_empty() {
+                self.waiters.remove(&key);
+            }
+        }
+    }
+
+    /// Check if the waiter drives the network request of the segment, i.e. it is the one that
+    /// retries on timeout. The first waiter still waiting is the driver, so if it goes away
+    /// the next one takes over.
+    pub fn is_driver(&mut self, key: SegmentKey, waiter_id: u64) -> bool {
+        let Some(waiters) = self.waiters.get_mut(&key) else {
+            return false;
+        };
+        waiters.retain(|(_, sender)| !sender.is_closed());
+        waiters.first().is_some_and(|(id, _)| *id == waiter_id)
+    }
+
+    /// Check if some endpoint is waiting for the segment
+    pub fn is_pending(&self, key: SegmentKey) -> bool {
+        self.waiters.contains_key(&key)
+    }
+
+    /// Number of segments waiting for a network response
+    pub fn pending_count(&self) -> usize {
+        self.waiters.len()
+    }
+}
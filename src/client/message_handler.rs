@@ -1,78 +1,178 @@
-use super::{ClientAudio, Status};
-use crossbeam_channel::TryRecvError;
+use super::task::ClientTask;
+use super::{ClientAudio, ClientState, Status};
+use crate::error::ClientError;
+use crossbeam_channel::{select, Receiver, RecvError, RecvTimeoutError};
+use std::sync::RwLockWriteGuard;
 use std::thread;
+use std::time::{Duration, Instant};
+use wg_internal::controller::DroneCommand;
+use wg_internal::packet::Packet;
 mod command_handler;
 mod packet_handler;
+mod task_handler;
+
+/// What woke up the message loop
+enum Wakeup {
+    Command(Result<DroneCommand, RecvError>),
+    Packet(Result<Packet, RecvError>),
+    Task(Result<ClientTask, RecvError>),
+    Shutdown,
+    Timeout,
+}
 
 impl ClientAudio {
     /// The root function of the message handler thread, it will loop until the status of the client is set to Terminated
     /// It will handle the commands and packets received by the drones or the simulation controller
+    ///
+    /// The thread blocks until a message arrives or the next scheduled work is due (a retransmission,
+    /// a flood request or the expiry of a message in reassembly), and takes the state lock once per wakeup
+    /// to handle the message and the scheduled work, so the rocket endpoints are not starved.
     pub(crate) fn start_message_processing(self) -> thread::JoinHandle<()> {
         let mut init_state = self.state.write().unwrap();
         Self::init_flood_request(&mut init_state);
+        let controller_recv = init_state.controller_recv.clone();
+        let packet_recv = init_state.packet_recv.clone();
+        let task_recv = init_state.task_recv.clone();
+        let mut timeout = Self::next_wakeup(&init_state);
         drop(init_state);
 
         self.refresh_network();
 
         thread::spawn(move || loop {
-            let mut state = self.state.write().unwrap();
-
-            // If the client is starting and the server is detected, we initialize the connection with the server
-            if !state.servers_id.is_empty() && state.status == Status::Starting {
-                state
-                    .logger
-                    .log_info("Server detected, intialize server connection");
-                Self::send_subscribe(&mut state);
-                state.status = Status::Idle;
-            }
-
-            if state.status == Status::Terminated {
-                break;
-            }
+            let wakeup = select! {
+                recv(controller_recv) -> command => Wakeup::Command(command),
+                recv(packet_recv) -> packet => Wakeup::Packet(packet),
+                recv(task_recv) -> task => Wakeup::Task(task),
+                // the client has been shut down by the rocket side
+                recv(self.shutdown_recv) -> _ => Wakeup::Shutdown,
+                default(timeout) => Wakeup::Timeout,
+            };
 
-            // handler for the drone messages
-            match state.controller_recv.try_recv() {
-                Ok(command) => Self::command_handler(&mut state, command),
-                Err(TryRecvError::Empty) => {}
-                Err(e) => {
+            let mut state = self.state.write().unwrap();
+            match wakeup {
+                // handler for the simulation controller commands
+                Wakeup::Command(Ok(command)) => Self::command_handler(&mut state, command),
+                Wakeup::Command(Err(e)) => {
                     state.logger.log_error(&format!(
                         "[{}, {}], error receiving command: {e:?}",
                         file!(),
                         line!()
                     ));
+                    // nobody can send commands anymore, stop the client
+                    Self::shutdown(&mut state);
                 }
-            }
-
-            // handler for the simulation controller commands
-            match state.packet_recv.try_recv() {
-                Ok(packet) => Self::packet_handler(&mut state, packet),
-                Err(TryRecvError::Empty) => {}
-                Err(e) => {
+                // handler for the drone messages
+                Wakeup::Packet(Ok(packet)) => Self::packet_handler(&mut state, packet),
+                Wakeup::Packet(Err(e)) => {
                     state.logger.log_error(&format!(
                         "[{}, {}], error receiving packet: {e:?}, ",
                         file!(),
                         line!()
                     ));
+                    // nobody can send packets anymore, stop the client
+                    Self::shutdown(&mut state);
                 }
+                // handler for the work posted by the rocket endpoints and the background threads,
+                // the client holds a sender so the channel is never disconnected
+                Wakeup::Task(Ok(task)) => Self::task_handler(&mut state, task),
+                Wakeup::Task(Err(_)) | Wakeup::Shutdown | Wakeup::Timeout => {}
+            }
+
+            // send again the fragments that have not been acked in time and forget the too old ones
+            Self::retransmit_expired(&mut state);
+            Self::expire_history(&mut state);
+            Self::prune_reassembly(&mut state);
+            Self::flood_if_needed(&mut state);
+
+            // If the client is starting and the server is detected, we initialize the connection with the servers
+            if !state.servers_id.is_empty() && state.status() == Status::Starting {
+                state
+                    .logger
+                    .log_info("Server detected, intialize server connection");
+                Self::send_subscribe(&mut state);
+                state.set_status(Status::Idle);
+            }
+
+            // the endpoints and the simulation controller read the state from the snapshot
+            state.publish_snapshot();
+
+            if state.status() == Status::Terminated {
+                break;
             }
+            timeout = Self::next_wakeup(&state);
         })
     }
 
-    /// Thread that will refresh the network every 60 seconds by sending a request filelist and a flood request.
+    /// Time until the next scheduled work: a fragment to send again or to give up,
+    /// a flood request or a message in reassembly to drop
+    fn next_wakeup(state: &ClientState) -> Duration {
+        let now = Instant::now();
+        let next = [
+            state.packets_history.next_deadline(),
+            state.reassembly.next_expiry(),
+        ]
+        .into_iter()
+        .flatten()
+        .fold(state.flood_scheduler.next_flood(now), Instant::min);
+        next.saturating_duration_since(now)
+    }
+
+    /// Thread that will periodically refresh the file list, and the subscriptions if the local library changed
+    /// or a server has been lost. The interval is read from the client settings and the wait ends as soon as the client shuts down.
+    /// The flood requests are scheduled by the message loop.
     pub(crate) fn refresh_network(&self) -> thread::JoinHandle<()> {
-        let state = self.state.clone();
+        let client = self.clone();
         thread::spawn(move || loop {
-            if state.write().unwrap().status == Status::Terminated {
+            let filelist_refresh = Duration::from_secs(client.config.filelist_refresh_secs);
+            if Self::wait_shutdown(&client.shutdown_recv, filelist_refresh) {
                 break;
             }
-
-            if state.write().unwrap().status == Status::Running {
-                thread::sleep(std::time::Duration::from_secs(20));
-                Self::send_request_filelist(&mut state.write().unwrap());
-
-                thread::sleep(std::time::Duration::from_secs(60));
-                Self::init_flood_request(&mut state.write().unwrap());
+            // nothing to refresh until the client is connected to the servers
+            if client.status() != Status::Running {
+                continue;
+            }
+            // the processing thread stopped
+            if client.post(ClientTask::RefreshNetwork).is_err() {
+                break;
             }
         })
     }
+
+    /// Wait for the given time, returns true if the client shut down in the meantime
+    fn wait_shutdown(shutdown_recv: &Receiver<()>, timeout: Duration) -> bool {
+        !matches!(
+            shutdown_recv.recv_timeout(timeout),
+            Err(RecvTimeoutError::Timeout)
+        )
+    }
+
+    /// Stop the client in an orderly way: unsubscribe from the servers, fail the requests still
+    /// waiting for the network, flush the database and wake up the threads waiting for the shutdown.
+    pub(crate) fn shutdown(state: &mut RwLockWriteGuard<ClientState>) {
+        // the client is already shut down
+        let Some(shutdown_send) = state.shutdown_send.take() else {
+            return;
+        };
+        state.logger.log_info("Shutting down the client");
+
+        Self::send_unsubscribe(state);
+
+        let failed = state
+            .inflight
+            .lock()
+            .unwrap()
+            .fail_all(ClientError::Terminated);
+        if failed > 0 {
+            state
+                .logger
+                .log_info(&format!("Cancelled {} pending segment requests", failed));
+        }
+
+        if let Err(e) = state.db.flush() {
+            state.logger.log_error(&e.to_string());
+        }
+
+        state.set_status(Status::Terminated);
+        drop(shutdown_send);
+    }
 }
@@ -1,4 +1,5 @@
 use super::ClientAudio;
+use crate::error::{ClientError, ClientResult};
 use crate::{ClientState, Status};
 use crossbeam_channel::Sender;
 use std::sync::RwLockWriteGuard;
@@ -10,23 +11,30 @@ impl ClientAudio {
         state: &mut RwLockWriteGuard<ClientState>,
         command: DroneCommand,
     ) {
-        if state.status != Status::Terminated {
+        if state.status() != Status::Terminated {
             let res = match command {
                 DroneCommand::RemoveSender(id) => Self::remove_sender(state, id),
                 DroneCommand::AddSender(id, sender) => Self::add_sender(state, id, &sender),
-                // when the simulation controller sends a crash command, change the status to terminated
+                // when the simulation controller sends a crash command, shut down the client
                 DroneCommand::Crash => {
                     state
                         .logger
                         .log_debug("[SC COMMAND]]Received crash command. Terminating!");
-                    state.status = Status::Terminated;
+                    Self::shutdown(state);
+                    Ok(())
+                }
+                // the client does not forward packets, so there is no drop rate to apply
+                DroneCommand::SetPacketDropRate(pdr) => {
+                    state.logger.log_debug(&format!(
+                        "[SC COMMAND] Ignoring packet drop rate {}, clients do not drop packets",
+                        pdr
+                    ));
                     Ok(())
                 }
-                _ => Err("[SC COMMAND]Received unhandled SC command (ChangePdr)!".to_string()),
             };
 
             if let Err(err) = res {
-                state.logger.log_error(&err);
+                state.logger.log_error(&err.to_string());
             }
         }
     }
@@ -58,12 +66,12 @@ impl ClientAudio {
     pub(crate) fn sc_send_packet(
         sender: &Sender<DroneEvent>,
         packet: &DroneEvent,
-    ) -> Result<(), String> {
+    ) -> ClientResult<()> {
         match sender.send(packet.clone()) {
             Ok(()) => Ok(()),
-            Err(err) => Err(format!(
+            Err(err) => Err(ClientError::Send(format!(
                 "Error occurred while sending packet event to SC. Error: {err}"
-            )),
+            ))),
         }
     }
 
@@ -71,12 +79,15 @@ impl ClientAudio {
     pub(crate) fn remove_sender(
         state: &mut RwLockWriteGuard<ClientState>,
         id: NodeId,
-    ) -> Result<(), String> {
+    ) -> ClientResult<()> {
         let res = state.senders.remove(&id);
         if res.is_none() {
-            return Err(format!("[REMOVE SENDER] - Sender with id {} not found", id));
+            return Err(ClientError::NoNeighbour(id));
         }
-        Self::init_flood_request(state);
+        // stop routing through the removed neighbour right away
+        let own_id = state.id;
+        Self::remove_link(state, own_id, id);
+        Self::request_flood(state);
         state
             .logger
             .log_debug(&format!("[REMOVE SENDER] - Sender with id {} removed", id));
@@ -88,15 +99,15 @@ impl ClientAudio {
         state: &mut RwLockWriteGuard<ClientState>,
         id: NodeId,
         sender: &Sender<Packet>,
-    ) -> Result<(), String> {
+    ) -> ClientResult<()> {
         let res = state.senders.insert(id, sender.clone());
         if res.is_some() {
-            return Err(format!(
+            return Err(ClientError::Command(format!(
                 "[ADD SENDER] - Sender with id {} already exists",
                 id
-            ));
+            )));
         }
-        Self::init_flood_request(state);
+        Self::request_flood(state);
         state
             .logger
             .log_debug(&format!("[ADD SENDER] - Sender with id {} added", id));
@@ -1,8 +1,9 @@
 use super::ClientAudio;
-use crate::ClientState;
+use crate::error::{ClientError, ClientResult};
+use crate::{ClientState, Status};
 use crossbeam_channel::Sender;
 use rocket::form::validate::Contains;
-use std::{collections::HashMap, sync::RwLockWriteGuard};
+use std::{collections::HashMap, sync::RwLockWriteGuard, time::Instant};
 use wg_internal::{
     network::NodeId,
     packet::{NodeType, Packet, PacketType},
@@ -27,6 +28,8 @@ impl ClientAudio {
                     ));
                     return;
                 }
+                // count the packet for the nodes of its path, for the network inspection endpoints
+                state.topology.record_traffic(&packet.routing_header.hops);
             }
         }
 
@@ -48,15 +51,31 @@ impl ClientAudio {
                     flood_res.flood_id
                 ));
                 state.routing_handler.update_graph(flood_res.clone());
+                state.topology.record(flood_res);
+                state.flood_scheduler.observe(&flood_res.path_trace);
                 for (id, node_type) in &flood_res.path_trace {
-                    if *node_type == NodeType::Server && !state.servers_id.contains(id) {
+                    if *node_type != NodeType::Server {
+                        continue;
+                    }
+                    if !state.servers_id.contains(id) {
                         state.logger.log_info(&format!("Adding server id: {}", id));
                         state.servers_id.push(*id);
+                    } else if !state.subscribed_servers.contains(id) {
+                        state
+                            .logger
+                            .log_info(&format!("[SERVER-{}] is reachable again", id));
+                    }
+                    // servers discovered after the first one, or found again after being lost,
+                    // are subscribed as soon as they show up
+                    if state.status() != Status::Starting
+                        && !state.subscribed_servers.contains(id)
+                    {
+                        Self::subscribe_server(state, *id);
                     }
                 }
             }
             PacketType::FloodRequest(flood_req) => {
-                Self::handle_flood_request(state, flood_req);
+                Self::handle_flood_request(state, flood_req, packet.session_id);
             }
             PacketType::Ack(ack) => {
                 state
@@ -68,12 +87,7 @@ impl ClientAudio {
                 state
                     .routing_handler
                     .nodes_congestion(packet.routing_header.clone());
-                Self::nack_handler(
-                    state,
-                    nack,
-                    packet.session_id,
-                    packet.routing_header.hops[0],
-                );
+                Self::nack_handler(state, nack, packet.session_id, &packet.routing_header.hops);
             }
         }
     }
@@ -83,38 +97,38 @@ impl ClientAudio {
         state: &mut RwLockWriteGuard<ClientState>,
         packets: &[Packet],
         next_hop: NodeId,
-    ) -> Result<(), String> {
+    ) -> ClientResult<()> {
         // Get the sender channel for the next hop and forward
-        let sender = Self::get_sender(next_hop, &state.senders);
-        if sender.is_err() {
-            return Err(format!("{}", &sender.unwrap_err()));
-        }
-        let sender = sender.unwrap();
+        let sender = Self::get_sender(next_hop, &state.senders)?;
 
         for packet in packets {
             let packet_str = Self::get_packet_type(&packet.pack_type);
             if let Err(err) = Self::send_packet(&sender, packet) {
-                return Err(format!(
+                return Err(ClientError::Send(format!(
                     "Failed to send packet to [DRONE-{}].\nPacket: {}\n Error: {}",
                     next_hop, packet, err
-                ));
+                )));
+            }
+            // only fragments are acked, the other packets are not kept for retransmission
+            if let PacketType::MsgFragment(_) = packet.pack_type {
+                state.packets_history.insert(
+                    (packet.get_fragment_index(), packet.session_id),
+                    packet.clone(),
+                    Instant::now(),
+                );
             }
-            state.packets_history.insert(
-                (packet.get_fragment_index(), packet.session_id),
-                packet.clone(),
-            );
             Self::event_dispatcher(state, packet, &packet_str);
         }
         Ok(())
     }
 
     /// Send a packet to the next hop
-    pub(crate) fn send_packet(sender: &Sender<Packet>, packet: &Packet) -> Result<(), String> {
+    pub(crate) fn send_packet(sender: &Sender<Packet>, packet: &Packet) -> ClientResult<()> {
         match sender.send(packet.clone()) {
             Ok(()) => Ok(()),
-            Err(err) => Err(format!(
+            Err(err) => Err(ClientError::Send(format!(
                 "Tried sending packet: {packet} but an error occurred: {err}"
-            )),
+            ))),
         }
     }
 
@@ -122,11 +136,11 @@ impl ClientAudio {
     pub fn get_sender(
         node_id: NodeId,
         senders: &HashMap<NodeId, Sender<Packet>>,
-    ) -> Result<Sender<Packet>, String> {
+    ) -> ClientResult<Sender<Packet>> {
         if let Some(sender) = senders.get(&node_id) {
             return Ok(sender.clone());
         }
-        Err(format!("No neigbour of ID [{node_id}] found."))
+        Err(ClientError::NoNeighbour(node_id))
     }
 
     /// Returns the `PacketType` formatted as as `String`
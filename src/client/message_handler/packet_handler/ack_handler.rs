@@ -12,14 +12,19 @@ impl ClientAudio {
         fragment_index: u64,
         routing_header: SourceRoutingHeader,
     ) {
+        state.topology.record_ack(&routing_header.hops);
         state.routing_handler.nodes_ack(routing_header);
-        let Some(_) = state.packets_history.remove(&(fragment_index, session_id)) else {
-            state.logger.log_error(&format!(
-                "Failed to remove [ ({}, {}) ] key from packet history",
+        if state
+            .packets_history
+            .remove(&(fragment_index, session_id))
+            .is_none()
+        {
+            // duplicated ack or ack of a fragment that has already been given up
+            state.logger.log_debug(&format!(
+                "Received ack for [ ({}, {}) ] which is not in the packet history",
                 fragment_index, session_id
             ));
-            return;
-        };
+        }
     }
 
     // Builds and sends an `Ack` to the `next_hop`. If it fails it tries to use the Simulation Controller
@@ -41,7 +46,7 @@ impl ClientAudio {
         let ack = Packet::new_ack(source_routing_header, packet.session_id, fragment_index);
 
         if let Err(msg) = Self::send_packets_vec(state, &[ack], next_hop) {
-            state.logger.log_error(&msg);
+            state.logger.log_error(&msg.to_string());
             state
                 .logger
                 .log_debug(&format!("[ACK] Trying to use SC shortcut..."));
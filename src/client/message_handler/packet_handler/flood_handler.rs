@@ -1,6 +1,10 @@
 use super::ClientAudio;
+use crate::error::{ClientError, ClientResult};
 use crate::ClientState;
+use packet_forge::SessionIdT;
+use std::collections::HashSet;
 use std::sync::RwLockWriteGuard;
+use std::time::Instant;
 use wg_internal::{
     controller::DroneEvent,
     network::{NodeId, SourceRoutingHeader},
@@ -10,8 +14,14 @@ use wg_internal::{
 impl ClientAudio {
     /// Initiate a flood request broadcast to all drones
     pub(crate) fn init_flood_request(state: &mut RwLockWriteGuard<ClientState>) {
+        let flood_id = Self::get_flood_id(state);
+
+        // forget the paths that the previous floods did not confirm
+        let expired = state.topology.expire(flood_id);
+        Self::remove_links(state, &expired);
+
         let flood_req = FloodRequest {
-            flood_id: Self::get_flood_id(state),
+            flood_id,
             initiator_id: state.id,
             path_trace: vec![(state.id, NodeType::Client)],
         };
@@ -31,41 +41,101 @@ impl ClientAudio {
             let packet_str = Self::get_packet_type(&packet.pack_type);
             Self::event_dispatcher(state, &packet, &packet_str);
         }
+
+        state.flood_scheduler.flooded(Instant::now());
+        state.logger.log_debug(&format!(
+            "[FLOODING] Next periodic flood in {:?}",
+            state.flood_scheduler.interval()
+        ));
+    }
+
+    /// Forget the link between the two nodes, the known paths through it and the links known only from those paths
+    pub(crate) fn remove_link(state: &mut RwLockWriteGuard<ClientState>, a: NodeId, b: NodeId) {
+        let mut removed = state.topology.remove_link(a, b);
+        // the routing graph may know the link from a flood response that is not kept anymore
+        let link = (a.min(b), a.max(b));
+        if !removed.contains(&link) {
+            removed.push(link);
+        }
+        Self::remove_links(state, &removed);
+    }
+
+    /// Remove the edges from the routing graph, keeping the weights learned for the other nodes,
+    /// then drop the servers that cannot be reached anymore. The flood scheduler counts the removal as churn.
+    pub(crate) fn remove_links(
+        state: &mut RwLockWriteGuard<ClientState>,
+        edges: &[(NodeId, NodeId)],
+    ) {
+        if edges.is_empty() {
+            return;
+        }
+        for (a, b) in edges {
+            state.routing_handler.remove_edge(*a, *b);
+        }
+        state.flood_scheduler.removed(edges);
+        state.logger.log_debug(&format!(
+            "[FLOODING] Removed the links {:?} from the routing graph",
+            edges
+        ));
+
+        Self::drop_unreachable_servers(state);
+    }
+
+    /// Ask for a flood request because the topology changed. Requests close in time are coalesced
+    /// in a single flood, sent by the message loop when the rate limit allows it.
+    pub(crate) fn request_flood(state: &mut RwLockWriteGuard<ClientState>) {
+        state.flood_scheduler.request();
+    }
+
+    /// Send a flood request if one has been requested or the periodic interval elapsed
+    pub(crate) fn flood_if_needed(state: &mut RwLockWriteGuard<ClientState>) {
+        if state.flood_scheduler.poll(Instant::now()) {
+            Self::init_flood_request(state);
+        }
     }
 
-    /// Build a flood response for the received flood request
+    /// Answer the received flood request with a flood response.
+    /// The client does not forward flood requests, it is an endpoint of the network.
     pub(crate) fn handle_flood_request(
         state: &mut RwLockWriteGuard<ClientState>,
         message: &FloodRequest,
+        session_id: SessionIdT,
     ) {
-        let (dest, packet) = Self::build_flood_response(state.id,message);
+        // a flood started by the client came back through a loop of drones
+        if message.initiator_id == state.id {
+            state.logger.log_debug(&format!(
+                "[FLOODING] Ignoring own flood request {}",
+                message.flood_id
+            ));
+            return;
+        }
 
-        let res = Self::send_flood_response(state, dest, &packet);
+        let (dest, packet) = match Self::build_flood_response(state.id, message, session_id) {
+            Ok(response) => response,
+            Err(e) => {
+                state
+                    .logger
+                    .log_warn(&format!("[FLOODING] Refusing flood request: {}", e));
+                return;
+            }
+        };
 
-        if let Err(msg) = res {
-            state.logger.log_error(&msg);
+        if let Err(msg) = Self::send_flood_response(state, dest, &packet) {
+            state.logger.log_error(&msg.to_string());
         }
     }
 
-    /// Send the flood response back to the sender
+    /// Send the flood response back to the sender, through the SC shortcut if the sender cannot be reached
     fn send_flood_response(
         state: &mut RwLockWriteGuard<ClientState>,
-        sender: NodeId,
+        next_hop: NodeId,
         packet: &Packet,
-    ) -> Result<(), String> {
-
-        let sender = Self::get_sender(sender, &state.senders);
-
-        if let Err(err) = sender {
-            return Err(format!(
-                "[FLOOD RESPONSE] - Error occurred while sending flood response: {}",
-                err
-            ));
-        }
+    ) -> ClientResult<()> {
+        let sent = Self::get_sender(next_hop, &state.senders)
+            .and_then(|sender| Self::send_packet(&sender, packet));
 
-        let sender = sender.unwrap();
-        if let Err(err) = Self::send_packet(&sender, packet) {
-            state.logger.log_warn(&format!("[FLOOD RESPONSE] - Failed to forward packet to [DRONE-{}]. \n Error: {} \n Trying to use SC shortcut...", packet.routing_header.current_hop().unwrap(), err));
+        if let Err(err) = sent {
+            state.logger.log_warn(&format!("[FLOOD RESPONSE] - Failed to forward packet to [DRONE-{}]. \n Error: {} \n Trying to use SC shortcut...", next_hop, err));
             // Send to SC
             let res = Self::sc_send_packet(
                 &state.controller_send,
@@ -76,10 +146,10 @@ impl ClientAudio {
                 state
                     .logger
                     .log_error(&format!("[FLOOD RESPONSE] - {}", err));
-                return Err(format!(
+                return Err(ClientError::Send(format!(
                     "[FLOOD RESPONSE] - Unable to forward packet to neither next hop nor SC. \n Packet: {}",
                     packet
-                ));
+                )));
             }
 
             state.logger.log_debug(&format!(
@@ -95,18 +165,157 @@ impl ClientAudio {
         state.flood_id
     }
 
-    /// Build a flood response for the received flood request
-    pub(crate) fn build_flood_response(node_id: NodeId, flood_req: &FloodRequest) -> (NodeId, Packet) {
+    /// Build the flood response for the received flood request.
+    /// Returns the next hop towards the initiator and the packet, or an error if the request is malformed
+    /// or was started by the client itself.
+    pub(crate) fn build_flood_response(
+        node_id: NodeId,
+        flood_req: &FloodRequest,
+        session_id: SessionIdT,
+    ) -> ClientResult<(NodeId, Packet)> {
+        if flood_req.initiator_id == node_id {
+            return Err(ClientError::InvalidData(format!(
+                "flood {} was started by this client",
+                flood_req.flood_id
+            )));
+        }
+        if flood_req.path_trace.first().map(|(id, _)| *id) != Some(flood_req.initiator_id) {
+            return Err(ClientError::InvalidData(format!(
+                "path trace of flood {} does not start with the initiator [NODE-{}]",
+                flood_req.flood_id, flood_req.initiator_id
+            )));
+        }
+        let mut visited = HashSet::new();
+        if !flood_req
+            .path_trace
+            .iter()
+            .all(|(id, _)| *id != node_id && visited.insert(*id))
+        {
+            return Err(ClientError::InvalidData(format!(
+                "path trace of flood {} contains a loop",
+                flood_req.flood_id
+            )));
+        }
+
         let mut flood_req = flood_req.clone();
         flood_req.path_trace.push((node_id, NodeType::Client));
-        let mut packet = flood_req.generate_response(1); // Note: returns with hop_index = 0;
+        let mut packet = flood_req.generate_response(session_id); // Note: returns with hop_index = 0;
         packet.routing_header.increase_hop_index();
-        let dest = packet.routing_header.current_hop();
 
-        if dest.is_none() {
-            return (0, packet);
+        match packet.routing_header.current_hop() {
+            Some(dest) => Ok((dest, packet)),
+            None => Err(ClientError::InvalidData(format!(
+                "flood response {} has no next hop",
+                flood_req.flood_id
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ClientAudio;
+    use crate::error::ClientError;
+    use wg_internal::network::NodeId;
+    use wg_internal::packet::{FloodRequest, NodeType, PacketType};
+
+    const CLIENT_ID: NodeId = 10;
+
+    fn flood_request(initiator_id: NodeId, path_trace: Vec<(NodeId, NodeType)>) -> FloodRequest {
+        FloodRequest {
+            flood_id: 7,
+            initiator_id,
+            path_trace,
         }
+    }
+
+    #[test]
+    fn response_goes_back_along_the_path_trace() {
+        let request = flood_request(
+            1,
+            vec![
+                (1, NodeType::Server),
+                (2, NodeType::Drone),
+                (3, NodeType::Drone),
+            ],
+        );
+
+        let (dest, packet) = ClientAudio::build_flood_response(CLIENT_ID, &request, 42).unwrap();
+
+        assert_eq!(dest, 3);
+        assert_eq!(packet.session_id, 42);
+        assert_eq!(packet.routing_header.hops, vec![CLIENT_ID, 3, 2, 1]);
+        assert_eq!(packet.routing_header.hop_index, 1);
+        let PacketType::FloodResponse(response) = packet.pack_type else {
+            panic!("expected a flood response");
+        };
+        assert_eq!(response.flood_id, 7);
+        assert_eq!(
+            response.path_trace.last(),
+            Some(&(CLIENT_ID, NodeType::Client))
+        );
+    }
+
+    #[test]
+    fn own_flood_is_not_answered() {
+        let request = flood_request(
+            CLIENT_ID,
+            vec![(CLIENT_ID, NodeType::Client), (2, NodeType::Drone)],
+        );
+
+        let result = ClientAudio::build_flood_response(CLIENT_ID, &request, 42);
+
+        assert!(matches!(result, Err(ClientError::InvalidData(_))));
+    }
+
+    #[test]
+    fn empty_path_trace_is_refused() {
+        let request = flood_request(1, Vec::new());
+
+        let result = ClientAudio::build_flood_response(CLIENT_ID, &request, 42);
+
+        assert!(matches!(result, Err(ClientError::InvalidData(_))));
+    }
+
+    #[test]
+    fn path_trace_not_starting_with_the_initiator_is_refused() {
+        let request = flood_request(1, vec![(2, NodeType::Drone), (3, NodeType::Drone)]);
+
+        let result = ClientAudio::build_flood_response(CLIENT_ID, &request, 42);
+
+        assert!(matches!(result, Err(ClientError::InvalidData(_))));
+    }
+
+    #[test]
+    fn path_trace_with_a_loop_is_refused() {
+        let request = flood_request(
+            1,
+            vec![
+                (1, NodeType::Server),
+                (2, NodeType::Drone),
+                (3, NodeType::Drone),
+                (2, NodeType::Drone),
+            ],
+        );
+
+        let result = ClientAudio::build_flood_response(CLIENT_ID, &request, 42);
+
+        assert!(matches!(result, Err(ClientError::InvalidData(_))));
+    }
+
+    #[test]
+    fn path_trace_already_containing_the_client_is_refused() {
+        let request = flood_request(
+            1,
+            vec![
+                (1, NodeType::Server),
+                (CLIENT_ID, NodeType::Client),
+                (2, NodeType::Drone),
+            ],
+        );
+
+        let result = ClientAudio::build_flood_response(CLIENT_ID, &request, 42);
 
-        (dest.unwrap(), packet)
+        assert!(matches!(result, Err(ClientError::InvalidData(_))));
     }
 }
@@ -1,7 +1,12 @@
 use super::ClientAudio;
+use crate::client::downloads::MAX_SEGMENTS_PER_REQUEST;
+use crate::client::progress::ProgressEvent;
+use crate::client::reassembly::Reassembly;
+use crate::error::ClientError;
 use crate::{ClientState, Status};
 use packet_forge::{FileMetadata, Index, MessageType};
 use std::sync::RwLockWriteGuard;
+use std::time::Instant;
 use wg_internal::packet::{Fragment, Packet};
 
 impl ClientAudio {
@@ -14,32 +19,98 @@ impl ClientAudio {
         let client_id = packet.routing_header.hops[0];
         let key = (client_id, packet.session_id);
 
-        // Save fragment
-        let total_fragments = fragment.total_n_fragments;
-        state
-            .packets_map
-            .entry(key)
-            .or_default()
-            .push(fragment.clone());
+        // Save fragment, the message is assembled once all of its fragments are received
+        let mut fragments = match state.reassembly.insert(key, fragment, Instant::now()) {
+            Reassembly::Incomplete { received } => {
+                Self::send_ack(state, packet, fragment.fragment_index);
+                let segment = state
+                    .requested_segments
+                    .session_segment(client_id, packet.session_id);
+                state.emit_progress(ProgressEvent::Fragment {
+                    from: client_id,
+                    session_id: packet.session_id,
+                    file_id: segment.map(|(file_id, _)| file_id),
+                    segment: segment.map(|(_, segment)| segment),
+                    received,
+                    total: fragment.total_n_fragments,
+                });
+                return;
+            }
+            // the ack of the fragment may have been lost, send it again
+            Reassembly::Duplicate => {
+                state.logger.log_debug(&format!(
+                    "Received duplicated fragment {} of session {} from [NODE-{}]",
+                    fragment.fragment_index, packet.session_id, client_id
+                ));
+                Self::send_ack(state, packet, fragment.fragment_index);
+                return;
+            }
+            Reassembly::Rejected(reason) => {
+                state.logger.log_warn(&format!(
+                    "Discarded fragment {} of session {} from [NODE-{}]: {}",
+                    fragment.fragment_index, packet.session_id, client_id, reason
+                ));
+                return;
+            }
+            Reassembly::Complete(fragments) => {
+                Self::send_ack(state, packet, fragment.fragment_index);
+                fragments
+            }
+        };
+        let received = fragments.len();
 
-        let mut fragments = state.packets_map.get(&key).unwrap().clone();
-        // Send Ack back to the Client
-        Self::send_ack(state, packet, fragment.fragment_index);
+        let assembled = match state.packet_forge.assemble_dynamic(&mut fragments) {
+            Ok(message) => message,
+            Err(e) => {
+                state
+                    .requested_segments
+                    .forget_session(client_id, packet.session_id);
+                state.logger.log_error(&format!(
+                    "An error occurred when assembling fragments: {}",
+                    e
+                ));
+                return;
+            }
+        };
 
-        // If all fragments are received, assemble the message
-        if fragments.len() as u64 == total_fragments {
-            let assembled = match state.packet_forge.assemble_dynamic(&mut fragments) {
-                Ok(message) => message,
-                Err(e) => {
-                    state.logger.log_error(&format!(
-                        "An error occurred when assembling fragments: {}",
-                        e
-                    ));
-                    return;
-                }
-            };
-            // menage the entire message
-            Self::handle_node_message(state, assembled);
+        // the assembled message tells which segment the session actually carried
+        let segment = match &assembled {
+            MessageType::ChunkResponse(chunk) => {
+                let segment = (chunk.file_hash, chunk.chunk_index);
+                state
+                    .requested_segments
+                    .received(client_id, packet.session_id, segment);
+                Some(segment)
+            }
+            _ => {
+                state
+                    .requested_segments
+                    .forget_session(client_id, packet.session_id);
+                None
+            }
+        };
+        state.emit_progress(ProgressEvent::Fragment {
+            from: client_id,
+            session_id: packet.session_id,
+            file_id: segment.map(|(file_id, _)| file_id),
+            segment: segment.map(|(_, segment)| segment),
+            received,
+            total: fragment.total_n_fragments,
+        });
+
+        // menage the entire message
+        Self::handle_node_message(state, assembled);
+    }
+
+    /// Drop the messages whose missing fragments did not arrive in time
+    pub(crate) fn prune_reassembly(state: &mut RwLockWriteGuard<ClientState>) {
+        let dropped = state.reassembly.prune(Instant::now());
+        if dropped > 0 {
+            state.logger.log_warn(&format!(
+                "Dropped {} incomplete messages, {} bytes still in reassembly",
+                dropped,
+                state.reassembly.bytes()
+            ));
         }
     }
 
@@ -49,49 +120,75 @@ impl ClientAudio {
         message: MessageType,
     ) {
         match message {
-            // When the chunk response is received, put the chunk in the buffer and send the event to the rocket endpoint
+            // When the chunk response is received, cache the chunk and send it to the waiting rocket endpoints
             MessageType::ChunkResponse(chunk) => {
                 state.logger.log_info(&format!(
                     "Received chunk response for file {}",
                     chunk.file_hash
                 ));
-                // get the channel corresponding to the chunk
-                let sender = state
-                    .inner_senders
-                    .get(&(chunk.file_hash, chunk.chunk_index))
-                    .cloned();
+                state
+                    .downloads
+                    .lock()
+                    .unwrap()
+                    .complete(chunk.file_hash, chunk.chunk_index);
+                state.emit_progress(ProgressEvent::SegmentReceived {
+                    file_id: chunk.file_hash,
+                    segment: chunk.chunk_index,
+                });
 
-                match sender {
-                    Some(sender) => {
-                        // Save chunk in the buffer for rocket
-                        state.song_map.insert(
-                            (chunk.file_hash, chunk.chunk_index),
-                            chunk.chunk_data.to_vec(),
-                        );
-                        // send the event to the rocket server
-                        sender.send(true).unwrap();
-                    }
-                    None => {
-                        state.logger.log_error(&format!(
-                            "No inner sender found for file {}",
-                            chunk.file_hash
-                        ));
+                // Keep a copy of the chunk so that the song can be replayed without the network
+                match state.db.insert_cached_segment(
+                    chunk.file_hash,
+                    chunk.chunk_index,
+                    chunk.chunk_data.to_vec(),
+                ) {
+                    // a new segment can only complete its song, an evicted one can only make its song incomplete
+                    Ok(mut songs) => {
+                        songs.retain(|song| state.announced_songs.contains(song));
+                        if !state.announced_songs.contains(&chunk.file_hash) {
+                            songs.push(chunk.file_hash);
+                        }
+                        Self::update_announced_songs(state, &songs);
                     }
+                    Err(e) => state
+                        .logger
+                        .log_error(&format!("Failed to cache segment: {}", e)),
+                }
+
+                // send the chunk to every rocket endpoint waiting for it
+                let notified = state.inflight.lock().unwrap().complete(
+                    (chunk.file_hash, chunk.chunk_index),
+                    Ok(chunk.chunk_data.to_vec()),
+                );
+                if notified == 0 {
+                    // the segment was requested in background by the download scheduler,
+                    // it is served from the segment cache when the browser asks for it
+                    state.logger.log_debug(&format!(
+                        "Received segment {} of file {} in background",
+                        chunk.chunk_index, chunk.file_hash
+                    ));
+                }
+
+                // once the playlist is known, start prefetching the first segments
+                if chunk.chunk_index == 0 {
+                    Self::schedule_download(state, chunk.file_hash, &chunk.chunk_data);
                 }
-                chunk.file_hash;
             }
             // When the file list is received, insert the new songs metadata in the database
             MessageType::ResponseFileList(list) => {
+                let mut new_songs = Vec::new();
                 for song in list.file_list {
                     match song {
                         FileMetadata::Song(song) => {
                             state
                                 .logger
                                 .log_info(&format!("Received song metadata: {}", song.id));
-                            if let Err(e) = state.db.insert_song_meta(song) {
-                                state
+                            match state.db.insert_song_meta(song) {
+                                Ok(id) if !state.announced_songs.contains(&id) => new_songs.push(id),
+                                Ok(_) => {}
+                                Err(e) => state
                                     .logger
-                                    .log_error(&format!("Failed to insert song metadata: {}", e));
+                                    .log_error(&format!("Failed to insert song metadata: {}", e)),
                             }
                         }
                         // this client does not handle video files
@@ -103,26 +200,37 @@ impl ClientAudio {
                         }
                     }
                 }
+                // metadata of cached songs is cleared on restart, advertise them again once it is known
+                Self::update_announced_songs(state, &new_songs);
                 // if the client is not already running, set the status to running
-                state.status = Status::Running;
+                state.set_status(Status::Running);
             }
-            // When the peer list is received, insert the peer in the client_song_map and send the first segment request to the node
+            // When the peer list is received, insert the peers ordered by path cost in the client_song_map and send them the segment requests waiting for the list
             MessageType::ResponsePeerList(list) => {
                 state
                     .logger
                     .log_info(&format!("Received peer list {:?}", list));
+                state.emit_progress(ProgressEvent::PeerList {
+                    file_id: list.file_hash,
+                    peers: list.peers.len(),
+                });
 
+                // the server does not know any peer for the file, another server may know one
                 if list.peers.is_empty() {
                     state
                         .logger
                         .log_warn(&format!("peer list is empty {:?}", list));
+                    if state.peer_lists.is_pending(list.file_hash) {
+                        let error = ClientError::NoPeer(list.file_hash);
+                        Self::ask_next_server(state, list.file_hash, error);
+                    }
                     return;
                 }
-                state
-                    .client_song_map
-                    .insert(list.file_hash, list.peers[0].client_id);
+                let peers = list.peers.iter().map(|peer| peer.client_id).collect();
+                let peers = Self::rank_by_path_cost(state, peers);
+                state.client_song_map.insert(list.file_hash, peers);
 
-                Self::send_internal_segment_request(state, list.file_hash, 0);
+                Self::request_waiting_segments(state, list.file_hash);
             }
             // When a peer asks for a chunk, send the chunk response to the node
             MessageType::ChunkRequest(chunk) => {
@@ -130,17 +238,19 @@ impl ClientAudio {
                     "Received chunk request for file {}",
                     chunk.file_hash
                 ));
-                if let Index::Indexes(vec) = &chunk.chunk_index {
-                    for chunk_index in vec {
-                        Self::send_chunk_response(
-                            state,
-                            chunk.file_hash,
-                            *chunk_index,
-                            chunk.client_id,
-                        );
+                // the index is chosen by the peer: only the first segments are answered and a huge range is never expanded
+                let indexes: Vec<u32> = match &chunk.chunk_index {
+                    Index::Indexes(vec) => {
+                        vec.iter().copied().take(MAX_SEGMENTS_PER_REQUEST).collect()
+                    }
+                    Index::Range(range) => range.clone().take(MAX_SEGMENTS_PER_REQUEST).collect(),
+                    _ => {
+                        state.logger.log_error("Invalid chunk index");
+                        return;
                     }
-                } else {
-                    state.logger.log_error("Invalid chunk index");
+                };
+                for chunk_index in indexes {
+                    Self::send_chunk_response(state, chunk.file_hash, chunk_index, chunk.client_id);
                 }
             }
             _ => {
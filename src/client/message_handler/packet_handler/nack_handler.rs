@@ -1,7 +1,10 @@
 use super::ClientAudio;
+use crate::client::packet_history::MAX_RETRANSMISSIONS;
+use crate::client::progress::ProgressEvent;
 use crate::ClientState;
 use packet_forge::SessionIdT;
 use std::sync::RwLockWriteGuard;
+use std::time::Instant;
 use wg_internal::{network::NodeId, packet::{Nack, NackType, Packet}};
 
 impl ClientAudio {
@@ -10,14 +13,22 @@ impl ClientAudio {
         state: &mut RwLockWriteGuard<ClientState>,
         message: &Nack,
         session_id: SessionIdT,
-        node_id: NodeId,
+        nack_hops: &[NodeId],
     ) {
+        // the node that sent the nack
+        let node_id = nack_hops[0];
         state.logger.log_warn(&format!(
             "Received Nack for [ ({}, {}) ]",
             message.fragment_index, session_id
         ));
+        state.emit_progress(ProgressEvent::Nack {
+            from: node_id,
+            session_id,
+            fragment_index: message.fragment_index,
+            kind: format!("{:?}", message.nack_type),
+        });
         // Retrieve the packet that generated the nack
-        let Some(mut packet) = state
+        let Some(packet) = state
             .packets_history
             .get(&(message.fragment_index, session_id))
             .cloned()
@@ -32,32 +43,129 @@ impl ClientAudio {
         match message.nack_type {
             NackType::Dropped => {
                 state.routing_handler.node_nack(node_id);
-                Self::retransmit_packet(state, &mut packet, message.fragment_index, session_id);
+                state.topology.record_nack(node_id);
+                Self::retransmit_limited(state, message.fragment_index, session_id);
             }
             NackType::DestinationIsDrone => {
-                state
-                    .logger
-                    .log_warn(&format!("Received DestinationIsDrone for {:?} ", packet));
+                let Some(&destination) = packet.routing_header.hops.last() else {
+                    return;
+                };
+                state.logger.log_warn(&format!(
+                    "Received DestinationIsDrone, [NODE-{}] is a drone, for {}",
+                    destination, packet
+                ));
+                // the message cannot be delivered to a drone: record the node as a drone in the routing graph,
+                // stop using it as a server or peer and send the request to another node
+                for response in state.topology.mark_drone(destination) {
+                    state.routing_handler.update_graph(response);
+                }
+                let owner = state.packets_history.drop_session(session_id);
+                Self::forget_endpoint(state, destination);
+                if let Some(owner) = owner {
+                    Self::reroute_session(state, owner);
+                }
+                Self::request_flood(state);
             }
             NackType::ErrorInRouting(node) => {
                 state.logger.log_warn(&format!(
                     "Received ErrorInRouting at [NODE-{}] for {}",
                     node, packet
                 ));
-                // Start new flooding
-                Self::init_flood_request(state);
-                // Retransmit packet
-                Self::retransmit_packet(state, &mut packet, message.fragment_index, session_id);
+                // the nacking node has no link to the next hop: forget the link, refresh the graph
+                // and retry along the new best path
+                Self::remove_link(state, node_id, node);
+                Self::request_flood(state);
+                Self::retransmit_limited(state, message.fragment_index, session_id);
             }
             NackType::UnexpectedRecipient(node) => {
                 state.logger.log_warn(&format!(
                     "Received UnexpectedRecipient at [NODE-{}] for {}",
                     node, packet
                 ));
+                // the node before the recipient forwarded the packet to another node than the next hop of the path:
+                // its link to the expected node is stale. Forget it, refresh the graph and retry along the new best path
+                state.topology.record_nack(node);
+                if let Some((previous, expected)) =
+                    Self::stale_link(&packet.routing_header.hops, nack_hops)
+                {
+                    Self::remove_link(state, previous, expected);
+                }
+                Self::request_flood(state);
+                Self::retransmit_limited(state, message.fragment_index, session_id);
             }
         }
     }
 
+    /// Link of the path that the node before the recipient of an UnexpectedRecipient nack did not follow.
+    /// The nack travels back along the path, so its second hop is the node that forwarded the packet.
+    fn stale_link(path: &[NodeId], nack_hops: &[NodeId]) -> Option<(NodeId, NodeId)> {
+        let previous = *nack_hops.get(1)?;
+        let position = path.iter().position(|hop| *hop == previous)?;
+        path.get(position + 1).map(|expected| (previous, *expected))
+    }
+
+    /// Retransmit the fragments whose ack did not arrive in time
+    pub(crate) fn retransmit_expired(state: &mut RwLockWriteGuard<ClientState>) {
+        for (fragment_index, session_id) in state.packets_history.expired(Instant::now()) {
+            // the session may have been dropped by a previous fragment in this loop
+            if state
+                .packets_history
+                .get(&(fragment_index, session_id))
+                .is_none()
+            {
+                continue;
+            }
+            state.logger.log_warn(&format!(
+                "No ack received for [ ({}, {}) ]",
+                fragment_index, session_id
+            ));
+            Self::retransmit_limited(state, fragment_index, session_id);
+        }
+    }
+
+    /// Send the fragment again counting the attempt. When the fragment reaches the retry limit
+    /// the whole session is dropped and the request it belongs to is failed.
+    fn retransmit_limited(
+        state: &mut RwLockWriteGuard<ClientState>,
+        fragment_index: u64,
+        session_id: SessionIdT,
+    ) {
+        match state
+            .packets_history
+            .retry(&(fragment_index, session_id), Instant::now())
+        {
+            Some(mut packet) => {
+                state.emit_progress(ProgressEvent::Retransmission {
+                    session_id,
+                    fragment_index,
+                });
+                Self::retransmit_packet(state, &mut packet, fragment_index, session_id);
+            }
+            None => {
+                state.logger.log_error(&format!(
+                    "Giving up [ ({}, {}) ] after {} retransmissions",
+                    fragment_index, session_id, MAX_RETRANSMISSIONS
+                ));
+                Self::give_up_session(state, session_id);
+            }
+        }
+    }
+
+    /// Forget the fragments of the session and fail the request it belongs to
+    fn give_up_session(state: &mut RwLockWriteGuard<ClientState>, session_id: SessionIdT) {
+        if let Some(owner) = state.packets_history.drop_session(session_id) {
+            Self::fail_session(state, owner);
+        }
+    }
+
+    /// Give up the fragments older than the maximum age of the history and the ones evicted to respect
+    /// its byte limit, failing the requests they belong to
+    pub(crate) fn expire_history(state: &mut RwLockWriteGuard<ClientState>) {
+        for owner in state.packets_history.expire(Instant::now()) {
+            Self::fail_session(state, owner);
+        }
+    }
+
     /// This function retransmit the packet for which the server received the Nack and tries to calculate a new optimal path.
     fn retransmit_packet(
         state: &mut RwLockWriteGuard<ClientState>,
@@ -83,7 +191,7 @@ impl ClientAudio {
         packet.routing_header = srh;
 
         if let Err(msg) = Self::send_packets_vec(state, &[packet.clone()], next_hop) {
-            state.logger.log_error(&msg);
+            state.logger.log_error(&msg.to_string());
             return;
         }
 
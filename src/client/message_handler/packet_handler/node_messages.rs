@@ -1,27 +1,44 @@
 use super::ClientAudio;
+use crate::client::downloads::DownloadScheduler;
+use crate::client::packet_history::SessionOwner;
+use crate::client::progress::ProgressEvent;
+use crate::database::playlist_segment_count;
+use crate::error::{ClientError, ClientResult};
 use crate::ClientState;
 use bytes::Bytes;
-use packet_forge::{FileMetadata, MessageType, RequestFileList, SubscribeClient};
+use packet_forge::{
+    FileHash, FileMetadata, Index, MessageType, RequestFileList, SubscribeClient,
+    UnsubscribeClient,
+};
+use std::collections::HashSet;
 use std::sync::RwLockWriteGuard;
 use wg_internal::network::NodeId;
 
 impl ClientAudio {
-    /// Send subscribe message to the server
+    /// Send subscribe message to every known server
     pub(crate) fn send_subscribe(state: &mut RwLockWriteGuard<ClientState>) {
+        for server_id in state.servers_id.clone() {
+            Self::subscribe_server(state, server_id);
+        }
+    }
+
+    /// Send subscribe message to the server
+    pub(crate) fn subscribe_server(state: &mut RwLockWriteGuard<ClientState>, server_id: NodeId) {
         let id = state.id;
-        let server_id = state.servers_id[0];
-
-        //Retrive avilable file from db
-        let file_list = match state.db.get_all_songs_meta() {
-            Ok(list) => list
-                .into_iter()
-                .map(|song| FileMetadata::Song(song))
-                .collect(),
+
+        //Retrive the local files and the fully cached songs from db
+        let songs = match state.db.get_seedable_songs_meta() {
+            Ok(songs) => songs,
             Err(e) => {
-                state.logger.log_error(&e);
+                state.logger.log_error(&e.to_string());
                 return;
             }
         };
+        let announced: HashSet<FileHash> = songs.iter().map(|song| song.id).collect();
+        let file_list = songs
+            .into_iter()
+            .map(|song| FileMetadata::Song(song))
+            .collect();
 
         let message = MessageType::SubscribeClient(SubscribeClient::new(
             id,
@@ -29,16 +46,101 @@ impl ClientAudio {
             file_list,
         ));
 
-        let _ = Self::send_message(state, message, id, server_id);
+        if Self::send_message(state, message, id, server_id).is_ok() {
+            state.subscribed_servers.insert(server_id);
+            state.announced_songs = announced;
+        }
+    }
+
+    /// Called periodically: send an updated subscription to every server if the songs that the client
+    /// can share changed since the last one, and subscribe again the servers that have been lost and are reachable now.
+    pub(crate) fn refresh_subscriptions(state: &mut RwLockWriteGuard<ClientState>) {
+        let songs: HashSet<FileHash> = match state.db.get_seedable_songs_meta() {
+            Ok(songs) => songs.into_iter().map(|song| song.id).collect(),
+            Err(e) => {
+                state.logger.log_error(&e.to_string());
+                return;
+            }
+        };
+
+        if songs != state.announced_songs {
+            state.logger.log_info(&format!(
+                "Local library changed {:?} -> {:?}, updating subscription",
+                state.announced_songs, songs
+            ));
+            Self::send_subscribe(state);
+            return;
+        }
+
+        let id = state.id;
+        for server_id in state.servers_id.clone() {
+            if !state.subscribed_servers.contains(&server_id)
+                && state.routing_handler.best_path(id, server_id).is_some()
+            {
+                Self::subscribe_server(state, server_id);
+            }
+        }
+    }
+
+    /// The server cannot be reached anymore: forget the subscription so that it is sent again
+    /// when the server shows up in a flood response
+    pub(crate) fn server_lost(state: &mut RwLockWriteGuard<ClientState>, server_id: NodeId) {
+        if state.subscribed_servers.remove(&server_id) {
+            state.logger.log_warn(&format!(
+                "[SERVER-{}] is not reachable, it will be subscribed again when rediscovered",
+                server_id
+            ));
+        }
+    }
+
+    /// Send unsubscribe message to every server the client is subscribed to
+    pub(crate) fn send_unsubscribe(state: &mut RwLockWriteGuard<ClientState>) {
+        let id = state.id;
+
+        for server_id in state.subscribed_servers.clone() {
+            let message = MessageType::UnsubscribeClient(UnsubscribeClient::new(id));
+            let _ = Self::send_message(state, message, id, server_id);
+        }
+        state.subscribed_servers.clear();
     }
 
-    /// Send request file list message to the server
+    /// Check again if the songs can be shared and, if this changed for some of them, send an updated
+    /// subscription so that the servers list this client as a peer only for the songs it actually has.
+    /// Only the given songs are checked, the periodic `refresh_subscriptions` catches any other change.
+    pub(crate) fn update_announced_songs(
+        state: &mut RwLockWriteGuard<ClientState>,
+        songs: &[FileHash],
+    ) {
+        let mut changed = Vec::new();
+        for &song in songs {
+            match state.db.is_song_seedable(song) {
+                Ok(seedable) if seedable != state.announced_songs.contains(&song) => {
+                    changed.push(song);
+                }
+                Ok(_) => {}
+                Err(e) => state.logger.log_error(&e.to_string()),
+            }
+        }
+
+        // the servers not subscribed yet receive the current songs when they are subscribed
+        if changed.is_empty() || state.subscribed_servers.is_empty() {
+            return;
+        }
+        state.logger.log_info(&format!(
+            "Songs {:?} can be shared or are no longer available, updating subscription",
+            changed
+        ));
+        Self::send_subscribe(state);
+    }
+
+    /// Send request file list message to every known server, the lists are merged in the database
     pub(crate) fn send_request_filelist(state: &mut RwLockWriteGuard<ClientState>) {
         let id = state.id;
-        let server_id = state.servers_id[0];
 
-        let message = MessageType::RequestFileList(RequestFileList::new(id));
-        let _ = Self::send_message(state, message, id, server_id);
+        for server_id in state.servers_id.clone() {
+            let message = MessageType::RequestFileList(RequestFileList::new(id));
+            let _ = Self::send_message(state, message, id, server_id);
+        }
     }
 
     // Send a chunk response to the destination node. Used in peer-to-peer communication.
@@ -49,10 +151,10 @@ impl ClientAudio {
         dst: NodeId,
     ) {
         let id = state.id;
-        let payload = match state.db.get_song_segment(file_id, segment) {
+        let payload = match state.db.get_segment(file_id, segment) {
             Ok(chunk) => chunk,
             Err(e) => {
-                state.logger.log_error(&e);
+                state.logger.log_error(&e.to_string());
                 return;
             }
         };
@@ -71,142 +173,362 @@ impl ClientAudio {
         file_id: u16,
         segment: u32,
     ) {
-        let id = state.id;
+        if let Err(e) = Self::send_chunk_request(state, file_id, Index::Indexes(vec![segment]), 0)
+        {
+            Self::notify_segment_failure(state, file_id, segment, e);
+        }
+    }
 
-        let dst = *state.client_song_map.get(&file_id).unwrap();
-        let message = MessageType::ChunkRequest(packet_forge::ChunkRequest::new(
-            id,
+    /// Save the number of segments listed in the playlist and start prefetching the first ones
+    pub(crate) fn schedule_download(
+        state: &mut RwLockWriteGuard<ClientState>,
+        file_id: u16,
+        playlist: &[u8],
+    ) {
+        let count = playlist_segment_count(playlist);
+        state
+            .downloads
+            .lock()
+            .unwrap()
+            .set_segment_count(file_id, count);
+        Self::schedule_prefetch(state, file_id, 0);
+    }
+
+    /// Request in parallel the segments that follow `segment` in the read-ahead window and are not available locally.
+    /// The segments are split in ranges that are spread round robin across the known peers.
+    pub(crate) fn schedule_prefetch(
+        state: &mut RwLockWriteGuard<ClientState>,
+        file_id: u16,
+        segment: u32,
+    ) {
+        // the prefetched segments are kept in the segment cache, with no budget they would be lost
+        if state.db.cache_budget() == 0 {
+            return;
+        }
+
+        let window = state.downloads.lock().unwrap().window(file_id, segment);
+        let missing: Vec<u32> = {
+            let downloads = state.downloads.lock().unwrap();
+            window
+                .filter(|segment| {
+                    !downloads.is_pending(file_id, *segment)
+                        && !state.db.contains_segment(file_id, *segment).unwrap_or(false)
+                })
+                .collect()
+        };
+        if missing.is_empty() {
+            return;
+        }
+
+        // the playlist has been read from the segment cache and the peers are not known yet,
+        // the segments are requested once the servers answer
+        if !state.client_song_map.contains_key(&file_id) {
+            Self::request_peer_list(state, file_id, &missing);
+            return;
+        }
+
+        let stripes = DownloadScheduler::plan(&missing);
+        state.logger.log_info(&format!(
+            "Prefetching {} segments of file {} in {} requests",
+            missing.len(),
             file_id,
-            packet_forge::Index::Indexes(vec![segment]),
+            stripes.len()
         ));
 
-        match Self::send_message(state, message, id, dst) {
-            Ok(()) => {
+        for (i, stripe) in stripes.into_iter().enumerate() {
+            let index = Index::Range(stripe.clone());
+            if Self::send_chunk_request(state, file_id, index, i).is_ok() {
                 state
-                    .logger
-                    .log_info(&format!("Successfully sent segment request"));
+                    .downloads
+                    .lock()
+                    .unwrap()
+                    .mark_pending(file_id, stripe);
             }
-            Err(()) => {
-                let sender = state.inner_senders.get(&(file_id, segment)).cloned();
+        }
+    }
 
-                match sender {
-                    Some(sender) => {
-                        // send the event to the rocket server
-                        sender.send(false).unwrap();
-                    }
-                    None => {
-                        state.logger.log_error(&format!(
-                            "No inner sender found for file {} to send error",
-                            file_id
-                        ));
-                    }
-                }
+    /// Send a segment request to the destination node, posted by the rocket endpoint on a cache miss.
+    pub(crate) fn request_segment(
+        state: &mut RwLockWriteGuard<ClientState>,
+        file_id: u16,
+        segment: u32,
+    ) {
+        // the segment has already been requested by the download scheduler, wait for it
+        if state.downloads.lock().unwrap().is_pending(file_id, segment) {
+            return;
+        }
 
-                state
-                    .logger
-                    .log_error(&format!("Failed to send segment request"));
+        // if the peers of the song are known send the request directly to them
+        if state.client_song_map.contains_key(&file_id) {
+            let index = Index::Indexes(vec![segment]);
+            if let Err(e) = Self::send_chunk_request(state, file_id, index, 0) {
+                Self::notify_segment_failure(state, file_id, segment, e);
             }
+            return;
         }
+
+        // the peers are not known, e.g. the playlist has been read from the segment cache after a restart:
+        // ask them to the servers, the segment is requested once they answer
+        Self::request_peer_list(state, file_id, &[segment]);
     }
 
-    /// Send a segment request to the destination node. Used by the rocket server.
-    pub(crate) fn send_segment_request(&mut self, file_id: u16, segment: u32) {
-        let mut state = self.state.write().unwrap();
-        let id = state.id;
-        let server_id = state.servers_id[0];
+    /// The request for the segment timed out: the current peer of the file is moved at the end of the list
+    /// and the request is sent again to the next one, at least once for each known peer.
+    /// When every peer has been tried the endpoints waiting for the segment get a timeout.
+    pub(crate) fn retry_segment(
+        state: &mut RwLockWriteGuard<ClientState>,
+        file_id: u16,
+        segment: u32,
+        attempt: usize,
+    ) {
+        // the background request for the segment is lost, the retry must go to the network
+        state.downloads.lock().unwrap().complete(file_id, segment);
 
-        // if the playlist is needed send peer list request to server
-        if segment == 0 {
+        let peer = state
+            .client_song_map
+            .get(&file_id)
+            .and_then(|peers| peers.first().copied());
+        if let Some(peer) = peer {
+            state.logger.log_warn(&format!(
+                "[CLIENT-{}] did not answer for file {}, failing over to the next peer",
+                peer, file_id
+            ));
+            state.requested_segments.forget(peer, file_id, &[segment]);
+            Self::demote_peer(state, file_id, peer);
+        }
+
+        let peers = state.client_song_map.get(&file_id).map_or(0, Vec::len);
+        if attempt < peers {
+            Self::request_segment(state, file_id, segment);
+            return;
+        }
+        state.logger.log_error(&format!(
+            "Timeout while waiting for segment {} of file {}",
+            segment, file_id
+        ));
+        Self::notify_segment_failure(state, file_id, segment, ClientError::Timeout);
+    }
+
+    /// Ask the peers of the file to the cheapest reachable server, the segments are requested to them once they are known.
+    /// The other servers are asked in turn if it answers with an empty list, disappears or never receives the request.
+    /// If the peers are already being asked, the segments wait for the same answer.
+    fn request_peer_list(
+        state: &mut RwLockWriteGuard<ClientState>,
+        file_id: u16,
+        segments: &[u32],
+    ) {
+        if state.peer_lists.wait(file_id, segments) {
+            return;
+        }
+        let servers = state.servers_id.clone();
+        let servers = Self::rank_by_path_cost(state, servers);
+        state.peer_lists.start(file_id, servers, segments);
+        Self::ask_next_server(state, file_id, ClientError::NoServer);
+    }
+
+    /// Send the segment requests that were waiting for the peers of the file
+    pub(crate) fn request_waiting_segments(state: &mut RwLockWriteGuard<ClientState>, file_id: u16) {
+        for segment in state.peer_lists.finish(file_id) {
+            Self::send_internal_segment_request(state, file_id, segment);
+        }
+    }
+
+    /// Send the peer list request to the next server that has not been asked yet.
+    /// When every server has been asked, the endpoints waiting for the segments of the file get `error`.
+    pub(crate) fn ask_next_server(
+        state: &mut RwLockWriteGuard<ClientState>,
+        file_id: u16,
+        error: ClientError,
+    ) {
+        let id = state.id;
+        let mut error = error;
+        while let Some(server_id) = state.peer_lists.next_server(file_id) {
             let message = MessageType::RequestPeerList(packet_forge::RequestPeerList {
                 client_id: id,
                 file_hash: file_id,
             });
 
-            match Self::send_message(&mut state, message, id, server_id) {
+            match Self::send_message(state, message, id, server_id) {
                 Ok(()) => {
-                    state
-                        .logger
-                        .log_info(&format!("Successfully sent segment request"));
+                    state.logger.log_info(&format!(
+                        "Successfully sent peer list request to [SERVER-{}]",
+                        server_id
+                    ));
+                    return;
                 }
-                Err(()) => {
-                    let sender = state.inner_senders.get(&(file_id, segment)).cloned();
+                Err(e) => error = e,
+            }
+        }
 
-                    match sender {
-                        Some(sender) => {
-                            // send the event to the rocket server
-                            sender.send(false).unwrap();
-                        }
-                        None => {
-                            state.logger.log_error(&format!(
-                                "No inner sender found for file {} to send error",
-                                file_id
-                            ));
-                        }
-                    }
+        state
+            .logger
+            .log_error(&format!("Failed to get the peer list of file {}: {}", file_id, error));
+        for segment in state.peer_lists.finish(file_id) {
+            Self::notify_segment_failure(state, file_id, segment, error.clone());
+        }
+    }
 
-                    state
-                        .logger
-                        .log_error(&format!("Failed to send segment request"));
-                }
+    /// The server cannot answer anymore: ask the next server for the peer lists it was asked for
+    fn fail_over_server(state: &mut RwLockWriteGuard<ClientState>, server_id: NodeId) {
+        for file_id in state.peer_lists.waiting_on(server_id) {
+            state.logger.log_warn(&format!(
+                "[SERVER-{}] is gone, asking the next server for the peers of file {}",
+                server_id, file_id
+            ));
+            Self::ask_next_server(state, file_id, ClientError::NoServer);
+        }
+    }
+
+    /// Send a chunk request to the `first`-th peer of the file (modulo the number of peers).
+    /// If the request cannot be sent the peer is moved at the end of the list and the next one is tried.
+    fn send_chunk_request(
+        state: &mut RwLockWriteGuard<ClientState>,
+        file_id: u16,
+        index: Index,
+        first: usize,
+    ) -> ClientResult<()> {
+        let id = state.id;
+        let peers = match state.client_song_map.get(&file_id) {
+            Some(peers) if !peers.is_empty() => peers.clone(),
+            _ => {
+                state
+                    .logger
+                    .log_error(&format!("No client found for file {}", file_id));
+                return Err(ClientError::NoPeer(file_id));
             }
-            return;
-        } else {
-            // else send the segment request to the peer
-
-            match state.client_song_map.get(&file_id) {
-                None => {
-                    state
-                        .logger
-                        .log_error(&format!("No client found for file {}", file_id));
-                    return;
-                }
-                Some(dst) => {
-                    let message = MessageType::ChunkRequest(packet_forge::ChunkRequest::new(
-                        id,
-                        file_id,
-                        packet_forge::Index::Indexes(vec![segment]),
-                    ));
+        };
 
-                    let dst_clone = *dst;
-                    match Self::send_message(&mut state, message, id, dst_clone) {
-                        Ok(()) => {
-                            state
-                                .logger
-                                .log_info(&format!("Successfully sent segment request"));
-                        }
-                        Err(()) => {
-                            let sender = state.inner_senders.get(&(file_id, segment)).cloned();
-
-                            match sender {
-                                Some(sender) => {
-                                    // send the event to the rocket server
-                                    sender.send(false).unwrap();
-                                }
-                                None => {
-                                    state.logger.log_error(&format!(
-                                        "No inner sender found for file {} to send error",
-                                        file_id
-                                    ));
-                                }
-                            }
-
-                            state
-                                .logger
-                                .log_error(&format!("Failed to send segment request"));
-                        }
-                    }
-                }
+        let mut peers = peers;
+        let first = first % peers.len();
+        peers.rotate_left(first);
+
+        for dst in peers {
+            let message = MessageType::ChunkRequest(packet_forge::ChunkRequest::new(
+                id,
+                file_id,
+                index.clone(),
+            ));
+
+            if let Err(e) = Self::send_message(state, message, id, dst) {
+                state.logger.log_warn(&format!(
+                    "Failed to send segment request to [CLIENT-{}]: {}, trying next peer",
+                    dst, e
+                ));
+                Self::demote_peer(state, file_id, dst);
+                continue;
+            }
+
+            state.logger.log_info(&format!(
+                "Successfully sent segment request to [CLIENT-{}]",
+                dst
+            ));
+            let segments = Self::index_segments(&index);
+            state.requested_segments.request(dst, file_id, &segments);
+            return Ok(());
+        }
+
+        state
+            .logger
+            .log_error(&format!("Failed to send segment request"));
+        Err(ClientError::NoPeer(file_id))
+    }
+
+    /// Move the peer at the end of the peer list of the file, so that the next request uses another peer
+    pub(crate) fn demote_peer(
+        state: &mut RwLockWriteGuard<ClientState>,
+        file_id: u16,
+        peer: NodeId,
+    ) {
+        if let Some(peers) = state.client_song_map.get_mut(&file_id) {
+            if let Some(pos) = peers.iter().position(|p| *p == peer) {
+                let peer = peers.remove(pos);
+                peers.push(peer);
             }
         }
     }
 
+    /// Remove the servers that have no path in the routing graph, they are added back
+    /// and subscribed again when they show up in a flood response
+    pub(crate) fn drop_unreachable_servers(state: &mut RwLockWriteGuard<ClientState>) {
+        let id = state.id;
+        for server_id in state.servers_id.clone() {
+            if state.routing_handler.best_path(id, server_id).is_none() {
+                state.logger.log_warn(&format!(
+                    "[SERVER-{}] is not reachable anymore, removing it",
+                    server_id
+                ));
+                state.servers_id.retain(|id| *id != server_id);
+                state.subscribed_servers.remove(&server_id);
+                Self::fail_over_server(state, server_id);
+            }
+        }
+    }
+
+    /// The node is not a client or a server: remove it from the servers and from the peers of every file
+    pub(crate) fn forget_endpoint(state: &mut RwLockWriteGuard<ClientState>, node_id: NodeId) {
+        state.servers_id.retain(|id| *id != node_id);
+        state.subscribed_servers.remove(&node_id);
+        Self::fail_over_server(state, node_id);
+        state.requested_segments.forget_peer(node_id);
+        for peers in state.client_song_map.values_mut() {
+            peers.retain(|peer| *peer != node_id);
+        }
+        // files without peers ask the server again for the peer list
+        state.client_song_map.retain(|_, peers| !peers.is_empty());
+    }
+
+    /// Sort the nodes (peers or servers) by the cost of the best path to reach them, unreachable nodes are put last
+    pub(crate) fn rank_by_path_cost(
+        state: &mut RwLockWriteGuard<ClientState>,
+        nodes: Vec<NodeId>,
+    ) -> Vec<NodeId> {
+        let id = state.id;
+        let mut ranked: Vec<(usize, NodeId)> = nodes
+            .into_iter()
+            .map(|node| {
+                let cost = state
+                    .routing_handler
+                    .best_path(id, node)
+                    .map_or(usize::MAX, |srh| srh.hops.len());
+                (cost, node)
+            })
+            .collect();
+        ranked.sort_by_key(|(cost, _)| *cost);
+        ranked.into_iter().map(|(_, node)| node).collect()
+    }
+
+    /// Tell the rocket endpoints waiting for the segment that the request failed
+    fn notify_segment_failure(
+        state: &mut RwLockWriteGuard<ClientState>,
+        file_id: u16,
+        segment: u32,
+        error: ClientError,
+    ) {
+        state.emit_progress(ProgressEvent::SegmentFailed {
+            file_id,
+            segment,
+            error: error.to_string(),
+        });
+        let notified = state
+            .inflight
+            .lock()
+            .unwrap()
+            .complete((file_id, segment), Err(error));
+        if notified == 0 {
+            state.logger.log_debug(&format!(
+                "No endpoint waiting for segment {} of file {}",
+                segment, file_id
+            ));
+        }
+    }
+
     /// Send a packet with the path to the destination node
     pub(crate) fn send_message(
         state: &mut RwLockWriteGuard<ClientState>,
         message: MessageType,
         src: NodeId,
         dst: NodeId,
-    ) -> Result<(), ()> {
+    ) -> ClientResult<()> {
         // for logging purposes
         let message_type = match message {
             MessageType::SubscribeClient(_) => "SubscribeClient",
@@ -222,28 +544,38 @@ impl ClientAudio {
         let srh = match state.routing_handler.best_path(src, dst) {
             Some(srh) => srh,
             None => {
-                state
-                    .logger
-                    .log_error(&format!("No path found from {} to {}!", src, dst));
-                return Err(());
+                let error = ClientError::NoRoute { from: src, to: dst };
+                state.logger.log_error(&error.to_string());
+                Self::server_lost(state, dst);
+                return Err(error);
             }
         };
 
+        // remember which request the session belongs to, to report it if its fragments are never acked
+        let owner = Self::session_owner(&message, dst);
+
         // disassemble the message into frames of the correct size
         let frames = match state.packet_forge.disassemble(message, &srh) {
             Ok(frames) => frames,
             Err(e) => {
-                state
-                    .logger
-                    .log_error(&format!("error on node messge disassemble: {}", e));
-                return Err(());
+                let error = ClientError::Disassembly(e.to_string());
+                state.logger.log_error(&error.to_string());
+                return Err(error);
             }
         };
 
         // send all the frames to the next hop
-        if let Err(e) = Self::send_packets_vec(state, &frames, srh.current_hop().unwrap()) {
-            state.logger.log_error(&e);
-            return Err(());
+        let Some(next_hop) = srh.current_hop() else {
+            return Err(ClientError::NoRoute { from: src, to: dst });
+        };
+        if let Err(e) = Self::send_packets_vec(state, &frames, next_hop) {
+            state.logger.log_error(&e.to_string());
+            Self::server_lost(state, dst);
+            return Err(e);
+        }
+
+        if let (Some(owner), Some(frame)) = (owner, frames.first()) {
+            state.packets_history.set_owner(frame.session_id, owner);
         }
 
         state
@@ -251,4 +583,118 @@ impl ClientAudio {
             .log_info(&format!("Successfully sent message {}", message_type));
         Ok(())
     }
+
+    /// Request or response that has to be reported if the message is never delivered
+    fn session_owner(message: &MessageType, dst: NodeId) -> Option<SessionOwner> {
+        match message {
+            MessageType::ChunkRequest(request) => Some(SessionOwner::ChunkRequest {
+                file_id: request.file_hash,
+                segments: Self::index_segments(&request.chunk_index),
+                peer: dst,
+            }),
+            MessageType::ChunkResponse(response) => Some(SessionOwner::ChunkResponse {
+                file_id: response.file_hash,
+                segment: response.chunk_index,
+                peer: dst,
+            }),
+            MessageType::RequestPeerList(request) => Some(SessionOwner::PeerList {
+                file_id: request.file_hash,
+                server: dst,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Segments listed by the index of a chunk request
+    fn index_segments(index: &Index) -> Vec<u32> {
+        match index {
+            Index::Indexes(segments) => segments.clone(),
+            Index::Range(range) => range.clone().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Send to another node the request of a session whose destination turned out to be a drone.
+    /// The node has already been forgotten as a peer and as a server, so the request goes to the next one.
+    pub(crate) fn reroute_session(state: &mut RwLockWriteGuard<ClientState>, owner: SessionOwner) {
+        match owner {
+            SessionOwner::ChunkRequest {
+                file_id,
+                segments,
+                peer,
+            } => {
+                state.logger.log_warn(&format!(
+                    "Sending the request for segments {:?} of file {} to [NODE-{}] to the next peer",
+                    segments, file_id, peer
+                ));
+                state.requested_segments.forget(peer, file_id, &segments);
+                if state.client_song_map.contains_key(&file_id) {
+                    let index = Index::Indexes(segments.clone());
+                    if let Err(e) = Self::send_chunk_request(state, file_id, index, 0) {
+                        for segment in segments {
+                            state.downloads.lock().unwrap().complete(file_id, segment);
+                            Self::notify_segment_failure(state, file_id, segment, e.clone());
+                        }
+                    }
+                    return;
+                }
+                // no peer left: the peers are asked again to the servers and the segments are requested once they answer
+                for segment in &segments {
+                    state.downloads.lock().unwrap().complete(file_id, *segment);
+                }
+                Self::request_peer_list(state, file_id, &segments);
+            }
+            SessionOwner::ChunkResponse {
+                file_id,
+                segment,
+                peer,
+            } => {
+                state.logger.log_warn(&format!(
+                    "Dropping chunk response for segment {} of file {}, [NODE-{}] is a drone",
+                    segment, file_id, peer
+                ));
+            }
+            // the request has already been moved to the next server when the node was forgotten
+            SessionOwner::PeerList { .. } => {}
+        }
+    }
+
+    /// Called when a fragment of the session reached the retransmission limit: fail the request it belongs to
+    pub(crate) fn fail_session(state: &mut RwLockWriteGuard<ClientState>, owner: SessionOwner) {
+        match owner {
+            SessionOwner::ChunkRequest {
+                file_id,
+                segments,
+                peer,
+            } => {
+                state.logger.log_error(&format!(
+                    "Chunk request for segments {:?} of file {} to [CLIENT-{}] was never delivered",
+                    segments, file_id, peer
+                ));
+                state.requested_segments.forget(peer, file_id, &segments);
+                Self::demote_peer(state, file_id, peer);
+                for segment in segments {
+                    state.downloads.lock().unwrap().complete(file_id, segment);
+                    Self::notify_segment_failure(state, file_id, segment, ClientError::Timeout);
+                }
+            }
+            SessionOwner::ChunkResponse {
+                file_id,
+                segment,
+                peer,
+            } => {
+                state.logger.log_error(&format!(
+                    "Chunk response for segment {} of file {} to [CLIENT-{}] was never delivered",
+                    segment, file_id, peer
+                ));
+            }
+            SessionOwner::PeerList { file_id, server } => {
+                state.logger.log_error(&format!(
+                    "Peer list request for file {} to [SERVER-{}] was never delivered",
+                    file_id, server
+                ));
+                Self::ask_next_server(state, file_id, ClientError::Timeout);
+            }
+        }
+    }
 }
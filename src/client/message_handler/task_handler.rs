@@ -0,0 +1,32 @@
+use super::ClientAudio;
+use crate::client::network_report::NetworkReport;
+use crate::client::task::ClientTask;
+use crate::ClientState;
+use std::sync::RwLockWriteGuard;
+
+impl ClientAudio {
+    /// Handles the work posted by the rocket endpoints and the background threads.
+    pub(crate) fn task_handler(state: &mut RwLockWriteGuard<ClientState>, task: ClientTask) {
+        match task {
+            ClientTask::Segment { file_id, segment } => {
+                Self::request_segment(state, file_id, segment);
+            }
+            ClientTask::Prefetch { file_id, segment } => {
+                Self::schedule_prefetch(state, file_id, segment);
+            }
+            ClientTask::Retry {
+                file_id,
+                segment,
+                attempt,
+            } => Self::retry_segment(state, file_id, segment, attempt),
+            ClientTask::RefreshNetwork => {
+                Self::send_request_filelist(state);
+                Self::refresh_subscriptions(state);
+            }
+            ClientTask::Network(reply) => {
+                // the endpoint stopped waiting
+                let _ = reply.send(NetworkReport::new(state));
+            }
+        }
+    }
+}
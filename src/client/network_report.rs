@@ -0,0 +1,118 @@
+use super::{ClientState, NodeStats};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use wg_internal::network::NodeId;
+
+/// Node of the routing graph
+#[derive(Debug, Clone, Serialize)]
+pub struct NetworkNode {
+    pub id: NodeId,
+    pub node_type: String,
+}
+
+/// Routing graph of the client, each edge is listed once with the lower id first
+#[derive(Debug, Clone, Serialize)]
+pub struct NetworkGraph {
+    pub nodes: Vec<NetworkNode>,
+    pub edges: Vec<(NodeId, NodeId)>,
+}
+
+/// Servers discovered by the flooding and the ones the client is subscribed to
+#[derive(Debug, Clone, Serialize)]
+pub struct NetworkServers {
+    pub servers: Vec<NodeId>,
+    pub subscribed: Vec<NodeId>,
+}
+
+/// Best path from the client to a server or to a peer, `hops` is missing if the node is unreachable
+#[derive(Debug, Clone, Serialize)]
+pub struct NetworkPath {
+    pub destination: NodeId,
+    pub kind: &'static str,
+    pub hops: Option<Vec<NodeId>>,
+}
+
+/// Network known by the client, built by the processing thread for the inspection endpoints
+#[derive(Debug, Clone)]
+pub struct NetworkReport {
+    pub graph: NetworkGraph,
+    pub servers: NetworkServers,
+    pub neighbours: Vec<NodeId>,
+    pub paths: Vec<NetworkPath>,
+    pub stats: BTreeMap<NodeId, NodeStats>,
+}
+
+impl NetworkReport {
+    pub fn new(state: &mut ClientState) -> Self {
+        let mut subscribed: Vec<NodeId> = state.subscribed_servers.iter().copied().collect();
+        subscribed.sort_unstable();
+        let mut neighbours: Vec<NodeId> = state.senders.keys().copied().collect();
+        neighbours.sort_unstable();
+
+        NetworkReport {
+            graph: Self::graph(state),
+            servers: NetworkServers {
+                servers: state.servers_id.clone(),
+                subscribed,
+            },
+            neighbours,
+            paths: Self::paths(state),
+            stats: state.topology.node_stats().clone(),
+        }
+    }
+
+    /// Edges of the routing handler, the node types are the ones reported by the flood responses
+    fn graph(state: &mut ClientState) -> NetworkGraph {
+        let edges: BTreeSet<(NodeId, NodeId)> = state
+            .routing_handler
+            .edges()
+            .into_iter()
+            .map(|(a, b)| (a.min(b), a.max(b)))
+            .collect();
+        let node_types = state.topology.nodes();
+        let ids: BTreeSet<NodeId> = edges.iter().flat_map(|(a, b)| [*a, *b]).collect();
+
+        let nodes = ids
+            .into_iter()
+            .map(|id| NetworkNode {
+                id,
+                node_type: node_types.get(&id).map_or_else(
+                    || "Unknown".to_string(),
+                    |node_type| format!("{:?}", node_type),
+                ),
+            })
+            .collect();
+
+        NetworkGraph {
+            nodes,
+            edges: edges.into_iter().collect(),
+        }
+    }
+
+    /// Current best path to every server and to every known peer
+    fn paths(state: &mut ClientState) -> Vec<NetworkPath> {
+        let id = state.id;
+        let mut peers: Vec<NodeId> = state.client_song_map.values().flatten().copied().collect();
+        peers.sort_unstable();
+        peers.dedup();
+
+        let destinations: Vec<(NodeId, &'static str)> = state
+            .servers_id
+            .iter()
+            .map(|server| (*server, "server"))
+            .chain(peers.into_iter().map(|peer| (peer, "peer")))
+            .collect();
+
+        destinations
+            .into_iter()
+            .map(|(destination, kind)| NetworkPath {
+                destination,
+                kind,
+                hops: state
+                    .routing_handler
+                    .best_path(id, destination)
+                    .map(|srh| srh.hops),
+            })
+            .collect()
+    }
+}
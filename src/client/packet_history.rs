@@ -0,0 +1,425 @@
+use packet_forge::{FileHash, SessionIdT};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::mem::size_of;
+use std::time::{Duration, Instant};
+use wg_internal::network::NodeId;
+use wg_internal::packet::Packet;
+
+/// Time waited for the ack of a fragment before the first retransmission, doubled at each retry
+pub const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(1);
+/// Number of retransmissions of a fragment before the message it belongs to is given up
+pub const MAX_RETRANSMISSIONS: u32 = 4;
+/// Default seconds after which a fragment that has never been acked is forgotten
+pub const DEFAULT_HISTORY_MAX_AGE_SECS: u64 = 60;
+/// Default number of bytes the fragments waiting for an ack are allowed to hold
+pub const DEFAULT_HISTORY_MAX_BYTES: u64 = 4 * 1024 * 1024;
+/// Smallest byte limit of the history: a fragment routed through every possible node must fit
+pub const MIN_HISTORY_MAX_BYTES: u64 =
+    (size_of::<Packet>() + (NodeId::MAX as usize + 1) * size_of::<NodeId>()) as u64;
+
+/// Key of a sent fragment: (fragment index, session id)
+pub type FragmentKey = (u64, SessionIdT);
+
+/// Request or response that generated a session, used to report the failure when a fragment is never acked
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionOwner {
+    ChunkRequest {
+        file_id: FileHash,
+        segments: Vec<u32>,
+        peer: NodeId,
+    },
+    ChunkResponse {
+        file_id: FileHash,
+        segment: u32,
+        peer: NodeId,
+    },
+    PeerList {
+        file_id: FileHash,
+        server: NodeId,
+    },
+}
+
+/// Counters of the retransmission history, exposed for debugging
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HistoryStats {
+    /// Fragments waiting for an ack
+    pub fragments: usize,
+    /// Sessions with at least one fragment waiting for an ack
+    pub sessions: usize,
+    /// Bytes held by the fragments waiting for an ack
+    pub bytes: u64,
+    pub max_bytes: u64,
+    /// Milliseconds since the oldest fragment waiting for an ack was first sent
+    pub oldest_age_ms: u64,
+    /// Fragments sent again because their ack did not arrive in time
+    pub retransmissions: u64,
+    /// Sessions given up after the retry limit or the maximum age
+    pub expired_sessions: u64,
+    /// Sessions dropped to keep the history under the byte limit
+    pub evicted_sessions: u64,
+}
+
+struct HistoryEntry {
+    packet: Packet,
+    // insertion order, used to find the oldest entries
+    seq: u64,
+    first_sent: Instant,
+    retries: u32,
+    deadline: Instant,
+    size: u64,
+}
+
+struct Session {
+    pending: usize,
+    owner: Option<SessionOwner>,
+}
+
+/// Fragments sent by the client and not yet acked, with the timers that drive their retransmission.
+/// Fragments older than the maximum age are forgotten and the oldest ones are dropped
+/// when the history holds more bytes than allowed.
+pub struct PacketHistory {
+    entries: HashMap<FragmentKey, HistoryEntry>,
+    sessions: HashMap<SessionIdT, Session>,
+    order: BTreeMap<u64, FragmentKey>,
+    next_seq: u64,
+    bytes: u64,
+    max_age: Duration,
+    max_bytes: u64,
+    // owners of the sessions evicted while inserting, returned by the next `expire`
+    evicted: Vec<SessionOwner>,
+    retransmissions: u64,
+    expired_sessions: u64,
+    evicted_sessions: u64,
+}
+
+impl PacketHistory {
+    pub fn new(max_age: Duration, max_bytes: u64) -> Self {
+        PacketHistory {
+            entries: HashMap::new(),
+            sessions: HashMap::new(),
+            order: BTreeMap::new(),
+            next_seq: 0,
+            bytes: 0,
+            max_age,
+            max_bytes,
+            evicted: Vec::new(),
+            retransmissions: 0,
+            expired_sessions: 0,
+            evicted_sessions: 0,
+        }
+    }
+
+    /// Change the maximum age of the fragments and the byte limit of the history
+    pub fn set_limits(&mut self, max_age: Duration, max_bytes: u64) {
+        self.max_age = max_age;
+        self.max_bytes = max_bytes;
+        self.evict();
+    }
+
+    /// Save a sent fragment and start its retransmission timer.
+    /// If the fragment is sent again (e.g. after a nack) the number of retries is kept.
+    pub fn insert(&mut self, key: FragmentKey, packet: Packet, now: Instant) {
+        if let Some(entry) = self.entries.get_mut(&key) {
+            entry.packet = packet;
+            entry.deadline = now + backoff(entry.retries);
+            return;
+        }
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let size = packet_size(&packet);
+        self.entries.insert(
+            key,
+            HistoryEntry {
+                packet,
+                seq,
+                first_sent: now,
+                retries: 0,
+                deadline: now + RETRANSMIT_TIMEOUT,
+                size,
+            },
+        );
+        self.order.insert(seq, key);
+        self.bytes += size;
+        self.sessions
+            .entry(key.1)
+            .or_insert(Session {
+                pending: 0,
+                owner: None,
+            })
+            .pending += 1;
+
+        self.evict();
+    }
+
+    /// Remember which request or response generated the session
+    pub fn set_owner(&mut self, session_id: SessionIdT, owner: SessionOwner) {
+        if let Some(session) = self.sessions.get_mut(&session_id) {
+            session.owner = Some(owner);
+        }
+    }
+
+    pub fn get(&self, key: &FragmentKey) -> Option<&Packet> {
+        self.entries.get(key).map(|entry| &entry.packet)
+    }
+
+    /// Remove an acked fragment, the session is forgotten once all its fragments are acked
+    pub fn remove(&mut self, key: &FragmentKey) -> Option<Packet> {
+        let entry = self.entries.remove(key)?;
+        self.order.remove(&entry.seq);
+        self.bytes -= entry.size;
+        if let Some(session) = self.sessions.get_mut(&key.1) {
+            session.pending -= 1;
+            if session.pending == 0 {
+                self.sessions.remove(&key.1);
+            }
+        }
+        Some(entry.packet)
+    }
+
+    /// Fragments whose ack did not arrive before their deadline
+    pub fn expired(&self, now: Instant) -> Vec<FragmentKey> {
+        self.entries
+            .iter()
+            .filter(|(_, entry)| entry.deadline <= now)
+            .map(|(key, _)| *key)
+            .collect()
+    }
+
+    /// Next time a fragment has to be sent again or a session becomes too old, `None` if the history is empty
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let retransmission = self.entries.values().map(|entry| entry.deadline).min();
+        let expiry = self
+            .order
+            .first_key_value()
+            .map(|(_, key)| self.entries[key].first_sent + self.max_age);
+        retransmission.into_iter().chain(expiry).min()
+    }
+
+    /// Count a retransmission of the fragment and move its deadline with exponential backoff.
+    /// Returns the packet to send again, or `None` if the fragment reached the retry limit.
+    pub fn retry(&mut self, key: &FragmentKey, now: Instant) -> Option<Packet> {
+        let entry = self.entries.get_mut(key)?;
+        if entry.retries >= MAX_RETRANSMISSIONS {
+            return None;
+        }
+        entry.retries += 1;
+        entry.deadline = now + backoff(entry.retries);
+        self.retransmissions += 1;
+        Some(entry.packet.clone())
+    }
+
+    /// Give up the session, returns the owner of the session if known
+    pub fn drop_session(&mut self, session_id: SessionIdT) -> Option<SessionOwner> {
+        self.expired_sessions += 1;
+        self.remove_session(session_id)
+    }
+
+    /// Give up the sessions with a fragment older than the maximum age.
+    /// Returns the owners of the dropped sessions, including the ones evicted to respect the byte limit.
+    pub fn expire(&mut self, now: Instant) -> Vec<SessionOwner> {
+        while let Some((_, &key)) = self.order.first_key_value() {
+            if now.duration_since(self.entries[&key].first_sent) < self.max_age {
+                break;
+            }
+            if let Some(owner) = self.drop_session(key.1) {
+                self.evicted.push(owner);
+            }
+        }
+        std::mem::take(&mut self.evicted)
+    }
+
+    pub fn stats(&self) -> HistoryStats {
+        let oldest_age_ms = self.order.first_key_value().map_or(0, |(_, key)| {
+            self.entries[key].first_sent.elapsed().as_millis() as u64
+        });
+
+        HistoryStats {
+            fragments: self.entries.len(),
+            sessions: self.sessions.len(),
+            bytes: self.bytes,
+            max_bytes: self.max_bytes,
+            oldest_age_ms,
+            retransmissions: self.retransmissions,
+            expired_sessions: self.expired_sessions,
+            evicted_sessions: self.evicted_sessions,
+        }
+    }
+
+    /// Drop the oldest sessions until the history fits in the byte limit
+    fn evict(&mut self) {
+        while self.bytes > self.max_bytes {
+            let Some((_, &(_, session_id))) = self.order.first_key_value() else {
+                break;
+            };
+            self.evicted_sessions += 1;
+            if let Some(owner) = self.remove_session(session_id) {
+                self.evicted.push(owner);
+            }
+        }
+    }
+
+    fn remove_session(&mut self, session_id: SessionIdT) -> Option<SessionOwner> {
+        // taken before removing the fragments, `remove` forgets the session with its last fragment
+        let owner = self
+            .sessions
+            .remove(&session_id)
+            .and_then(|session| session.owner);
+        let keys: Vec<FragmentKey> = self
+            .entries
+            .keys()
+            .filter(|(_, session)| *session == session_id)
+            .copied()
+            .collect();
+        for key in keys {
+            self.remove(&key);
+        }
+        owner
+    }
+}
+
+/// Time waited for the ack after the given number of retransmissions
+fn backoff(retries: u32) -> Duration {
+    RETRANSMIT_TIMEOUT * 2u32.saturating_pow(retries)
+}
+
+/// Approximate memory held by a packet in the history
+fn packet_size(packet: &Packet) -> u64 {
+    (size_of::<Packet>() + packet.routing_header.hops.len() * size_of::<NodeId>()) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        FragmentKey, PacketHistory, SessionOwner, MAX_RETRANSMISSIONS, RETRANSMIT_TIMEOUT,
+    };
+    use std::time::{Duration, Instant};
+    use wg_internal::network::SourceRoutingHeader;
+    use wg_internal::packet::Packet;
+
+    const MAX_AGE: Duration = Duration::from_secs(60);
+
+    fn packet(key: FragmentKey) -> Packet {
+        Packet::new_ack(SourceRoutingHeader::new(vec![10, 1, 20], 1), key.1, key.0)
+    }
+
+    fn owner(peer: u8) -> SessionOwner {
+        SessionOwner::PeerList {
+            file_id: 1,
+            server: peer,
+        }
+    }
+
+    #[test]
+    fn retry_backs_off_until_the_limit() {
+        let mut history = PacketHistory::new(MAX_AGE, u64::MAX);
+        let now = Instant::now();
+        let key = (0, 1);
+        history.insert(key, packet(key), now);
+        assert_eq!(history.next_deadline(), Some(now + RETRANSMIT_TIMEOUT));
+        assert!(history.expired(now).is_empty());
+        assert_eq!(history.expired(now + RETRANSMIT_TIMEOUT), vec![key]);
+
+        for retry in 1..=MAX_RETRANSMISSIONS {
+            assert!(history.retry(&key, now).is_some());
+            assert_eq!(
+                history.next_deadline(),
+                Some(now + RETRANSMIT_TIMEOUT * 2u32.pow(retry))
+            );
+        }
+
+        assert!(history.retry(&key, now).is_none());
+        assert_eq!(
+            history.stats().retransmissions,
+            u64::from(MAX_RETRANSMISSIONS)
+        );
+    }
+
+    #[test]
+    fn sending_a_fragment_again_keeps_its_retries() {
+        let mut history = PacketHistory::new(MAX_AGE, u64::MAX);
+        let now = Instant::now();
+        let key = (0, 1);
+        history.insert(key, packet(key), now);
+        for _ in 0..MAX_RETRANSMISSIONS {
+            history.retry(&key, now);
+        }
+
+        history.insert(key, packet(key), now);
+
+        assert!(history.retry(&key, now).is_none());
+        assert_eq!(history.stats().fragments, 1);
+    }
+
+    #[test]
+    fn session_is_forgotten_with_its_last_acked_fragment() {
+        let mut history = PacketHistory::new(MAX_AGE, u64::MAX);
+        let now = Instant::now();
+        history.insert((0, 1), packet((0, 1)), now);
+        history.insert((1, 1), packet((1, 1)), now);
+
+        history.remove(&(0, 1));
+        assert_eq!(history.stats().sessions, 1);
+        history.remove(&(1, 1));
+
+        let stats = history.stats();
+        assert_eq!(stats.sessions, 0);
+        assert_eq!(stats.bytes, 0);
+        assert_eq!(history.next_deadline(), None);
+    }
+
+    #[test]
+    fn expire_gives_up_the_sessions_older_than_the_maximum_age() {
+        let mut history = PacketHistory::new(MAX_AGE, u64::MAX);
+        let now = Instant::now();
+        history.insert((0, 1), packet((0, 1)), now);
+        history.set_owner(1, owner(1));
+        history.insert((0, 2), packet((0, 2)), now + MAX_AGE / 2);
+        history.set_owner(2, owner(2));
+
+        let expired = history.expire(now + MAX_AGE);
+
+        assert_eq!(expired, vec![owner(1)]);
+        let stats = history.stats();
+        assert_eq!(stats.sessions, 1);
+        assert_eq!(stats.expired_sessions, 1);
+        assert!(history.get(&(0, 2)).is_some());
+    }
+
+    #[test]
+    fn evict_drops_the_oldest_sessions_over_the_byte_limit() {
+        let now = Instant::now();
+        let mut sizing = PacketHistory::new(MAX_AGE, u64::MAX);
+        sizing.insert((0, 1), packet((0, 1)), now);
+        let size = sizing.stats().bytes;
+
+        let mut history = PacketHistory::new(MAX_AGE, 2 * size);
+        for session_id in 1..=3 {
+            history.insert((0, session_id), packet((0, session_id)), now);
+            history.set_owner(session_id, owner(session_id as u8));
+        }
+
+        let stats = history.stats();
+        assert_eq!(stats.fragments, 2);
+        assert_eq!(stats.bytes, 2 * size);
+        assert_eq!(stats.evicted_sessions, 1);
+        assert!(history.get(&(0, 1)).is_none());
+        // the owner of the evicted session is reported by the next expire
+        assert_eq!(history.expire(now), vec![owner(1)]);
+    }
+
+    #[test]
+    fn lower_byte_limit_evicts_immediately() {
+        let mut history = PacketHistory::new(MAX_AGE, u64::MAX);
+        let now = Instant::now();
+        history.insert((0, 1), packet((0, 1)), now);
+        history.insert((0, 2), packet((0, 2)), now);
+        let size = history.stats().bytes / 2;
+
+        history.set_limits(MAX_AGE, size);
+
+        assert_eq!(history.stats().fragments, 1);
+        assert!(history.get(&(0, 2)).is_some());
+    }
+}
@@ -0,0 +1,74 @@
+use packet_forge::FileHash;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use wg_internal::network::NodeId;
+
+struct PeerListRequest {
+    // server that has been asked last
+    server: Option<NodeId>,
+    // servers still to ask, best first
+    remaining: VecDeque<NodeId>,
+    // segments to request to the peers once they are known
+    segments: BTreeSet<u32>,
+}
+
+/// Peer list requests waiting for the answer of a server. If the server answers with an empty list
+/// or disappears from the topology, the request moves on to the next server.
+/// Each request keeps the segments of the file that are waiting for the peers.
+#[derive(Default)]
+pub struct PeerListRequests {
+    requests: HashMap<FileHash, PeerListRequest>,
+}
+
+impl PeerListRequests {
+    /// Start asking the servers for the peers of the file, in the given order, on behalf of the segments.
+    /// A request already in progress for the file starts over.
+    pub fn start(&mut self, file_id: FileHash, servers: Vec<NodeId>, segments: &[u32]) {
+        self.requests.insert(
+            file_id,
+            PeerListRequest {
+                server: None,
+                remaining: servers.into(),
+                segments: segments.iter().copied().collect(),
+            },
+        );
+    }
+
+    /// Add the segments to the ones waiting for the peers of the file.
+    /// Returns false if the peers of the file are not being asked.
+    pub fn wait(&mut self, file_id: FileHash, segments: &[u32]) -> bool {
+        let Some(request) = self.requests.get_mut(&file_id) else {
+            return false;
+        };
+        request.segments.extend(segments);
+        true
+    }
+
+    /// Next server to ask for the peers of the file, `None` once every server has been asked
+    pub fn next_server(&mut self, file_id: FileHash) -> Option<NodeId> {
+        let request = self.requests.get_mut(&file_id)?;
+        request.server = request.remaining.pop_front();
+        request.server
+    }
+
+    /// Check if the peers of the file are being asked to the servers
+    pub fn is_pending(&self, file_id: FileHash) -> bool {
+        self.requests.contains_key(&file_id)
+    }
+
+    /// Stop asking the peers of the file, because they have been received or every server has been asked.
+    /// Returns the segments that were waiting for them.
+    pub fn finish(&mut self, file_id: FileHash) -> Vec<u32> {
+        self.requests
+            .remove(&file_id)
+            .map_or_else(Vec::new, |request| request.segments.into_iter().collect())
+    }
+
+    /// Files whose peer list is waiting for the answer of the server
+    pub fn waiting_on(&self, server: NodeId) -> Vec<FileHash> {
+        self.requests
+            .iter()
+            .filter(|(_, request)| request.server == Some(server))
+            .map(|(file_id, _)| *file_id)
+            .collect()
+    }
+}
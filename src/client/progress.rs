@@ -0,0 +1,45 @@
+use packet_forge::{FileHash, SessionIdT};
+use serde::Serialize;
+use wg_internal::network::NodeId;
+
+/// Number of events kept for the slowest subscriber before it starts losing them
+pub const PROGRESS_CHANNEL_CAPACITY: usize = 256;
+
+/// Progress of the transfers, streamed to the frontend by the `/progress` endpoint.
+/// Segment 0 of a file is its playlist, segment N + 1 is `segmentN.ts`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProgressEvent {
+    /// The server answered with the peers that have the file
+    PeerList { file_id: FileHash, peers: usize },
+    /// A fragment of a message has been received. `file_id` and `segment` are the segment the message is expected
+    /// to carry, the one actually carried once the message is complete, and missing if it is not a segment
+    Fragment {
+        from: NodeId,
+        session_id: SessionIdT,
+        file_id: Option<FileHash>,
+        segment: Option<u32>,
+        received: usize,
+        total: u64,
+    },
+    /// A segment has been received from a peer
+    SegmentReceived { file_id: FileHash, segment: u32 },
+    /// A segment request failed
+    SegmentFailed {
+        file_id: FileHash,
+        segment: u32,
+        error: String,
+    },
+    /// A fragment has been sent again because its ack did not arrive in time
+    Retransmission {
+        session_id: SessionIdT,
+        fragment_index: u64,
+    },
+    /// A drone answered with a nack to a fragment sent by the client
+    Nack {
+        from: NodeId,
+        session_id: SessionIdT,
+        fragment_index: u64,
+        kind: String,
+    },
+}
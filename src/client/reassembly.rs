@@ -0,0 +1,334 @@
+use packet_forge::SessionIdT;
+use std::collections::{BTreeMap, HashMap};
+use std::mem::size_of_val;
+use std::time::{Duration, Instant};
+use wg_internal::network::NodeId;
+use wg_internal::packet::Fragment;
+
+/// Seconds after which a message that is still missing some fragments is dropped
+pub const STALE_SESSION_TIMEOUT: Duration = Duration::from_secs(30);
+/// Maximum size of a single message in reassembly
+pub const MAX_SESSION_BYTES: u64 = 16 * 1024 * 1024;
+/// Maximum number of bytes buffered for all the messages in reassembly
+pub const MAX_TOTAL_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Sender of the message and session id
+pub type SessionKey = (NodeId, SessionIdT);
+
+/// Result of adding a fragment to the reassembly buffer
+pub enum Reassembly {
+    /// Some fragments of the message are still missing, `received` fragments are buffered
+    Incomplete { received: usize },
+    /// The fragment has already been received
+    Duplicate,
+    /// All the fragments have been received, ordered by fragment index
+    Complete(Vec<Fragment>),
+    /// The fragment is not valid or does not fit in the buffer, it must not be acked
+    Rejected(String),
+}
+
+struct PartialMessage {
+    total_n_fragments: u64,
+    fragments: BTreeMap<u64, Fragment>,
+    bytes: u64,
+    last_update: Instant,
+}
+
+/// Fragments received from the other nodes, grouped by session until the whole message can be assembled.
+#[derive(Default)]
+pub struct ReassemblyBuffer {
+    sessions: HashMap<SessionKey, PartialMessage>,
+    // sessions already assembled with their number of fragments, so that late duplicates do not start a new message
+    completed: HashMap<SessionKey, (u64, Instant)>,
+    bytes: u64,
+}
+
+impl ReassemblyBuffer {
+    /// Add a fragment to its message
+    pub fn insert(&mut self, key: SessionKey, fragment: &Fragment, now: Instant) -> Reassembly {
+        let total = fragment.total_n_fragments;
+        if total == 0 || fragment.fragment_index >= total {
+            return Reassembly::Rejected(format!(
+                "fragment {} out of bounds, total {}",
+                fragment.fragment_index, total
+            ));
+        }
+        if total.saturating_mul(size_of_val(&fragment.data) as u64) > MAX_SESSION_BYTES {
+            return Reassembly::Rejected(format!("message of {} fragments is too big", total));
+        }
+        match self.completed.get(&key) {
+            Some((completed_total, _)) if *completed_total == total => {
+                return Reassembly::Duplicate;
+            }
+            // the sender reused the session id for a new message
+            Some(_) => {
+                self.completed.remove(&key);
+            }
+            None => {}
+        }
+
+        if let Some(partial) = self.sessions.get(&key) {
+            if partial.total_n_fragments != total {
+                return Reassembly::Rejected(format!(
+                    "total fragments changed from {} to {}",
+                    partial.total_n_fragments, total
+                ));
+            }
+            if partial.fragments.contains_key(&fragment.fragment_index) {
+                return Reassembly::Duplicate;
+            }
+        }
+
+        // the fragments of the other messages have already been acked and would never be sent again,
+        // so the new fragment is not acked and the sender retries it once there is room
+        let size = u64::from(fragment.length);
+        if self.bytes + size > MAX_TOTAL_BYTES {
+            return Reassembly::Rejected("reassembly buffer is full".to_string());
+        }
+
+        let partial = self.sessions.entry(key).or_insert(PartialMessage {
+            total_n_fragments: total,
+            fragments: BTreeMap::new(),
+            bytes: 0,
+            last_update: now,
+        });
+        partial
+            .fragments
+            .insert(fragment.fragment_index, fragment.clone());
+        partial.bytes += size;
+        partial.last_update = now;
+        self.bytes += size;
+
+        if partial.fragments.len() as u64 != total {
+            return Reassembly::Incomplete {
+                received: partial.fragments.len(),
+            };
+        }
+
+        let partial = self.sessions.remove(&key).unwrap();
+        self.bytes -= partial.bytes;
+        self.completed.insert(key, (total, now));
+        Reassembly::Complete(partial.fragments.into_values().collect())
+    }
+
+    /// Drop the messages that did not receive fragments for a while and forget the old completed sessions.
+    /// Returns the number of dropped messages.
+    pub fn prune(&mut self, now: Instant) -> usize {
+        self.completed
+            .retain(|_, (_, completed)| now.duration_since(*completed) < STALE_SESSION_TIMEOUT);
+
+        let stale: Vec<SessionKey> = self
+            .sessions
+            .iter()
+            .filter(|(_, partial)| now.duration_since(partial.last_update) >= STALE_SESSION_TIMEOUT)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in &stale {
+            self.remove(key);
+        }
+        stale.len()
+    }
+
+    /// Next time `prune` has something to drop, `None` if the buffer is empty
+    pub fn next_expiry(&self) -> Option<Instant> {
+        let partial = self.sessions.values().map(|partial| partial.last_update);
+        let completed = self.completed.values().map(|(_, completed)| *completed);
+        partial
+            .chain(completed)
+            .min()
+            .map(|oldest| oldest + STALE_SESSION_TIMEOUT)
+    }
+
+    /// Number of bytes buffered for the messages in reassembly
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    fn remove(&mut self, key: &SessionKey) {
+        if let Some(partial) = self.sessions.remove(key) {
+            self.bytes -= partial.bytes;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Reassembly, ReassemblyBuffer, SessionKey, MAX_SESSION_BYTES, MAX_TOTAL_BYTES,
+        STALE_SESSION_TIMEOUT,
+    };
+    use std::time::Instant;
+    use wg_internal::packet::{Fragment, FRAGMENT_DSIZE};
+
+    const KEY: SessionKey = (1, 42);
+
+    fn fragment(fragment_index: u64, total_n_fragments: u64) -> Fragment {
+        Fragment {
+            fragment_index,
+            total_n_fragments,
+            length: FRAGMENT_DSIZE as u8,
+            data: [fragment_index as u8; FRAGMENT_DSIZE],
+        }
+    }
+
+    #[test]
+    fn fragments_out_of_order_are_assembled_in_order() {
+        let mut buffer = ReassemblyBuffer::default();
+        let now = Instant::now();
+
+        assert!(matches!(
+            buffer.insert(KEY, &fragment(2, 3), now),
+            Reassembly::Incomplete { received: 1 }
+        ));
+        assert!(matches!(
+            buffer.insert(KEY, &fragment(0, 3), now),
+            Reassembly::Incomplete { received: 2 }
+        ));
+        let Reassembly::Complete(fragments) = buffer.insert(KEY, &fragment(1, 3), now) else {
+            panic!("expected a complete message");
+        };
+
+        let indexes: Vec<u64> = fragments.iter().map(|f| f.fragment_index).collect();
+        assert_eq!(indexes, vec![0, 1, 2]);
+        assert_eq!(buffer.bytes(), 0);
+    }
+
+    #[test]
+    fn duplicate_fragment_is_detected() {
+        let mut buffer = ReassemblyBuffer::default();
+        let now = Instant::now();
+
+        buffer.insert(KEY, &fragment(0, 2), now);
+        let bytes = buffer.bytes();
+
+        assert!(matches!(
+            buffer.insert(KEY, &fragment(0, 2), now),
+            Reassembly::Duplicate
+        ));
+        assert_eq!(buffer.bytes(), bytes);
+    }
+
+    #[test]
+    fn late_duplicate_of_a_completed_message_is_detected() {
+        let mut buffer = ReassemblyBuffer::default();
+        let now = Instant::now();
+
+        buffer.insert(KEY, &fragment(0, 1), now);
+
+        assert!(matches!(
+            buffer.insert(KEY, &fragment(0, 1), now),
+            Reassembly::Duplicate
+        ));
+    }
+
+    #[test]
+    fn reused_session_id_with_a_different_total_starts_a_new_message() {
+        let mut buffer = ReassemblyBuffer::default();
+        let now = Instant::now();
+
+        buffer.insert(KEY, &fragment(0, 1), now);
+
+        assert!(matches!(
+            buffer.insert(KEY, &fragment(0, 2), now),
+            Reassembly::Incomplete { received: 1 }
+        ));
+    }
+
+    #[test]
+    fn total_changed_in_the_middle_of_a_message_is_rejected() {
+        let mut buffer = ReassemblyBuffer::default();
+        let now = Instant::now();
+
+        buffer.insert(KEY, &fragment(0, 3), now);
+
+        assert!(matches!(
+            buffer.insert(KEY, &fragment(1, 2), now),
+            Reassembly::Rejected(_)
+        ));
+    }
+
+    #[test]
+    fn fragment_out_of_bounds_is_rejected() {
+        let mut buffer = ReassemblyBuffer::default();
+        let now = Instant::now();
+
+        assert!(matches!(
+            buffer.insert(KEY, &fragment(3, 3), now),
+            Reassembly::Rejected(_)
+        ));
+        assert!(matches!(
+            buffer.insert(KEY, &fragment(0, 0), now),
+            Reassembly::Rejected(_)
+        ));
+    }
+
+    #[test]
+    fn message_bigger_than_the_session_limit_is_rejected() {
+        let mut buffer = ReassemblyBuffer::default();
+        let total = MAX_SESSION_BYTES / FRAGMENT_DSIZE as u64 + 1;
+
+        let result = buffer.insert(KEY, &fragment(0, total), Instant::now());
+
+        assert!(matches!(result, Reassembly::Rejected(_)));
+        assert_eq!(buffer.bytes(), 0);
+    }
+
+    #[test]
+    fn full_buffer_rejects_new_fragments() {
+        let mut buffer = ReassemblyBuffer::default();
+        let now = Instant::now();
+        // the largest messages allowed, each one missing its last fragment
+        let total = MAX_SESSION_BYTES / FRAGMENT_DSIZE as u64;
+        let sessions = MAX_TOTAL_BYTES / MAX_SESSION_BYTES;
+        for session_id in 0..sessions {
+            for index in 0..total - 1 {
+                buffer.insert((1, session_id), &fragment(index, total), now);
+            }
+        }
+        // fill the room left by the missing fragments with another message
+        let room = (MAX_TOTAL_BYTES - buffer.bytes()) / FRAGMENT_DSIZE as u64;
+        for index in 0..room {
+            buffer.insert((2, 0), &fragment(index, room + 1), now);
+        }
+        let bytes = buffer.bytes();
+        assert_eq!(bytes, MAX_TOTAL_BYTES);
+
+        let result = buffer.insert((3, 0), &fragment(0, 2), now);
+
+        assert!(matches!(result, Reassembly::Rejected(_)));
+        assert_eq!(buffer.bytes(), bytes);
+    }
+
+    #[test]
+    fn prune_drops_stale_messages() {
+        let mut buffer = ReassemblyBuffer::default();
+        let now = Instant::now();
+        buffer.insert(KEY, &fragment(0, 2), now);
+        buffer.insert((2, 7), &fragment(0, 2), now + STALE_SESSION_TIMEOUT / 2);
+
+        let dropped = buffer.prune(now + STALE_SESSION_TIMEOUT);
+
+        assert_eq!(dropped, 1);
+        assert_eq!(buffer.bytes(), FRAGMENT_DSIZE as u64);
+        assert_eq!(
+            buffer.next_expiry(),
+            Some(now + STALE_SESSION_TIMEOUT / 2 + STALE_SESSION_TIMEOUT)
+        );
+    }
+
+    #[test]
+    fn prune_forgets_old_completed_messages() {
+        let mut buffer = ReassemblyBuffer::default();
+        let now = Instant::now();
+        buffer.insert(KEY, &fragment(0, 2), now);
+        buffer.insert(KEY, &fragment(1, 2), now);
+
+        buffer.prune(now + STALE_SESSION_TIMEOUT);
+
+        assert_eq!(buffer.next_expiry(), None);
+        assert!(matches!(
+            buffer.insert(KEY, &fragment(0, 2), now + STALE_SESSION_TIMEOUT),
+            Reassembly::Incomplete { received: 1 }
+        ));
+    }
+}
@@ -0,0 +1,69 @@
+use super::{ClientAudio, HistoryStats, Status};
+use packet_forge::FileHash;
+use serde::Serialize;
+use wg_internal::network::NodeId;
+
+/// Usage of the cache of the segments fetched from other peers
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheStats {
+    pub size_bytes: u64,
+    pub budget_bytes: u64,
+    pub segments: usize,
+}
+
+/// Segments that are being downloaded
+#[derive(Debug, Clone, Serialize)]
+pub struct DownloadsReport {
+    /// Segments requested in background and not received yet
+    pub pending: Vec<(FileHash, u32)>,
+    /// Segments requested by the frontend that are waiting for the network
+    pub waiting: usize,
+}
+
+/// Snapshot of the client, for the simulation controller
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientReport {
+    pub id: NodeId,
+    pub status: Status,
+    pub servers: Vec<NodeId>,
+    pub subscribed_servers: Vec<NodeId>,
+    pub cache: CacheStats,
+    pub downloads: DownloadsReport,
+    pub history: HistoryStats,
+}
+
+impl ClientAudio {
+    pub fn cache_stats(&self) -> CacheStats {
+        CacheStats {
+            size_bytes: self.db.cache_size(),
+            budget_bytes: self.db.cache_budget(),
+            segments: self.db.cached_segments(),
+        }
+    }
+
+    pub fn downloads_report(&self) -> DownloadsReport {
+        let waiting = self.inflight.lock().unwrap().pending_count();
+        DownloadsReport {
+            pending: self.downloads.lock().unwrap().pending_segments(),
+            waiting,
+        }
+    }
+
+    /// Status of the client, its servers, cache and downloads.
+    /// `DroneEvent` has no variant to carry it, the simulation controller gets it by downcasting
+    /// the client returned by `ClientT::as_any`.
+    /// The servers and the history are read from the snapshot of the last wakeup of the processing thread.
+    pub fn report(&self) -> ClientReport {
+        let snapshot = self.snapshot.read().unwrap().clone();
+
+        ClientReport {
+            id: self.id,
+            status: self.status(),
+            servers: snapshot.servers,
+            subscribed_servers: snapshot.subscribed_servers,
+            cache: self.cache_stats(),
+            downloads: self.downloads_report(),
+            history: snapshot.history,
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use super::inflight::SegmentKey;
+use packet_forge::{FileHash, SessionIdT};
+use std::collections::{HashMap, VecDeque};
+use wg_internal::network::NodeId;
+
+/// Segments requested to each peer and not received yet. The fragments of a response do not say which segment
+/// they carry until the whole message is assembled, so each session of a peer is matched with the oldest segment
+/// requested to the peer, and corrected once the message is assembled.
+/// Used to tell the frontend which segment is being buffered.
+#[derive(Default)]
+pub struct RequestedSegments {
+    requested: HashMap<NodeId, VecDeque<SegmentKey>>,
+    sessions: HashMap<(NodeId, SessionIdT), SegmentKey>,
+}
+
+impl RequestedSegments {
+    /// Save the segments of a chunk request sent to the peer
+    pub fn request(&mut self, peer: NodeId, file_id: FileHash, segments: &[u32]) {
+        let requested = self.requested.entry(peer).or_default();
+        for segment in segments {
+            if !requested.contains(&(file_id, *segment)) {
+                requested.push_back((file_id, *segment));
+            }
+        }
+    }
+
+    /// Segment that the session of the peer is expected to carry, `None` if nothing has been requested to the peer
+    pub fn session_segment(&mut self, peer: NodeId, session_id: SessionIdT) -> Option<SegmentKey> {
+        if let Some(key) = self.sessions.get(&(peer, session_id)) {
+            return Some(*key);
+        }
+
+        let key = self.requested.get(&peer)?.iter().copied().find(|key| {
+            !self
+                .sessions
+                .iter()
+                .any(|((other, _), assigned)| *other == peer && assigned == key)
+        })?;
+        self.sessions.insert((peer, session_id), key);
+        Some(key)
+    }
+
+    /// The session of the peer carried the segment, which is not expected anymore
+    pub fn received(&mut self, peer: NodeId, session_id: SessionIdT, key: SegmentKey) {
+        self.sessions.remove(&(peer, session_id));
+        self.remove(peer, |requested| *requested == key);
+    }
+
+    /// The session of the peer did not carry a segment
+    pub fn forget_session(&mut self, peer: NodeId, session_id: SessionIdT) {
+        self.sessions.remove(&(peer, session_id));
+    }
+
+    /// Stop expecting the segments from the peer, e.g. after the request failed over to another peer
+    pub fn forget(&mut self, peer: NodeId, file_id: FileHash, segments: &[u32]) {
+        self.remove(peer, |(file, segment)| {
+            *file == file_id && segments.contains(segment)
+        });
+    }
+
+    /// Stop expecting anything from the peer
+    pub fn forget_peer(&mut self, peer: NodeId) {
+        self.remove(peer, |_| true);
+    }
+
+    fn remove(&mut self, peer: NodeId, matches: impl Fn(&SegmentKey) -> bool) {
+        if let Some(requested) = self.requested.get_mut(&peer) {
+            requested.retain(|key| !matches(key));
+            if requested.is_empty() {
+                self.requested.remove(&peer);
+            }
+        }
+        self.sessions
+            .retain(|(other, _), key| *other != peer || !matches(key));
+    }
+}
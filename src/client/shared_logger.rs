@@ -0,0 +1,41 @@
+use logger::{LogLevel, Logger};
+use std::sync::{Arc, RwLock};
+
+/// Logger shared by the message processing thread and the rocket endpoints.
+/// It is synchronized on its own, so logging from an endpoint never waits for the packet processing.
+#[derive(Clone)]
+pub struct SharedLogger {
+    logger: Arc<RwLock<Logger>>,
+}
+
+impl SharedLogger {
+    pub fn new(logger: Logger) -> Self {
+        SharedLogger {
+            logger: Arc::new(RwLock::new(logger)),
+        }
+    }
+
+    pub fn log_info(&self, msg: &str) {
+        self.logger.read().unwrap().log_info(msg);
+    }
+
+    pub fn log_warn(&self, msg: &str) {
+        self.logger.read().unwrap().log_warn(msg);
+    }
+
+    pub fn log_error(&self, msg: &str) {
+        self.logger.read().unwrap().log_error(msg);
+    }
+
+    pub fn log_debug(&self, msg: &str) {
+        self.logger.read().unwrap().log_debug(msg);
+    }
+
+    pub fn add_displayable_flag(&self, level: LogLevel) {
+        self.logger.write().unwrap().add_displayable_flag(level);
+    }
+
+    pub fn init_web_socket(&self) {
+        self.logger.write().unwrap().init_web_socket();
+    }
+}
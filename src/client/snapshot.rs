@@ -0,0 +1,12 @@
+use super::packet_history::HistoryStats;
+use wg_internal::network::NodeId;
+
+/// Copy of the parts of the client state read by the endpoints and the simulation controller,
+/// published by the processing thread after every wakeup so that reading them never takes the state lock
+#[derive(Debug, Clone, Default)]
+pub struct StateSnapshot {
+    pub servers: Vec<NodeId>,
+    /// Sorted by id
+    pub subscribed_servers: Vec<NodeId>,
+    pub history: HistoryStats,
+}
@@ -0,0 +1,23 @@
+use super::network_report::NetworkReport;
+use packet_forge::FileHash;
+use tokio::sync::oneshot;
+
+/// Work handed to the message processing thread by the rocket endpoints and the background threads,
+/// so that they never take the state lock themselves.
+#[derive(Debug)]
+pub enum ClientTask {
+    /// A segment is not in the database, request it to the network
+    Segment { file_id: FileHash, segment: u32 },
+    /// Request in background the segments played after the given one
+    Prefetch { file_id: FileHash, segment: u32 },
+    /// The request for the segment timed out for the `attempt`-th time, retry with the next peer
+    Retry {
+        file_id: FileHash,
+        segment: u32,
+        attempt: usize,
+    },
+    /// Refresh the file list and the subscriptions
+    RefreshNetwork,
+    /// Answer with the network known by the client, for the inspection endpoints
+    Network(oneshot::Sender<NetworkReport>),
+}
@@ -0,0 +1,125 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use wg_internal::network::NodeId;
+use wg_internal::packet::{FloodResponse, NodeType};
+
+/// Number of previous floods for which a path is kept without being confirmed by a new flood response
+pub const KEPT_FLOODS: u64 = 2;
+
+/// Traffic seen by the client through a node, tracked next to the weights of the routing handler
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct NodeStats {
+    /// Packets received along a path through the node
+    pub packets: u64,
+    /// Acks received along a path through the node
+    pub acks: u64,
+    /// Nacks caused by the node
+    pub nacks: u64,
+}
+
+/// Paths learned from the flood responses, with the id of the last flood that confirmed them.
+/// The edges that are not part of a known path anymore are removed from the routing graph, so that
+/// the edges of removed or crashed nodes disappear once no recent flood response confirms them.
+#[derive(Default)]
+pub struct Topology {
+    paths: HashMap<Vec<NodeId>, (u64, FloodResponse)>,
+    stats: BTreeMap<NodeId, NodeStats>,
+}
+
+impl Topology {
+    /// Save the path of the flood response, or confirm it if it is already known
+    pub fn record(&mut self, response: &FloodResponse) {
+        let path = response.path_trace.iter().map(|(id, _)| *id).collect();
+        self.paths
+            .insert(path, (response.flood_id, response.clone()));
+    }
+
+    /// Forget the paths not confirmed by the last `KEPT_FLOODS` floods before `flood_id`.
+    /// Returns the edges that are not part of any known path anymore.
+    pub fn expire(&mut self, flood_id: u64) -> Vec<(NodeId, NodeId)> {
+        let before = self.edges();
+        self.paths
+            .retain(|_, (confirmed, _)| *confirmed + KEPT_FLOODS >= flood_id);
+        self.removed_edges(before)
+    }
+
+    /// Forget the paths that go through the link between the two nodes.
+    /// Returns the edges that are not part of any known path anymore.
+    pub fn remove_link(&mut self, a: NodeId, b: NodeId) -> Vec<(NodeId, NodeId)> {
+        let before = self.edges();
+        self.paths
+            .retain(|path, _| !path.windows(2).any(|link| link == [a, b] || link == [b, a]));
+        self.removed_edges(before)
+    }
+
+    /// Record that the node is a drone in the known paths, after a nack reported it.
+    /// Returns the corrected flood responses, to be fed again to the routing graph.
+    pub fn mark_drone(&mut self, node: NodeId) -> Vec<FloodResponse> {
+        let mut corrected = Vec::new();
+        for (_, response) in self.paths.values_mut() {
+            let mut changed = false;
+            for (id, node_type) in &mut response.path_trace {
+                if *id == node && *node_type != NodeType::Drone {
+                    *node_type = NodeType::Drone;
+                    changed = true;
+                }
+            }
+            if changed {
+                corrected.push(response.clone());
+            }
+        }
+        corrected
+    }
+
+    /// Edges of `before` that are not part of any known path
+    fn removed_edges(&self, before: BTreeSet<(NodeId, NodeId)>) -> Vec<(NodeId, NodeId)> {
+        let after = self.edges();
+        before.difference(&after).copied().collect()
+    }
+
+    /// Flood responses of the known paths
+    pub fn responses(&self) -> impl Iterator<Item = &FloodResponse> {
+        self.paths.values().map(|(_, response)| response)
+    }
+
+    /// Nodes of the known paths with their type
+    pub fn nodes(&self) -> BTreeMap<NodeId, NodeType> {
+        self.responses()
+            .flat_map(|response| response.path_trace.iter().cloned())
+            .collect()
+    }
+
+    /// Links of the known paths, each one listed once with the lower id first
+    pub fn edges(&self) -> BTreeSet<(NodeId, NodeId)> {
+        self.paths
+            .keys()
+            .flat_map(|path| {
+                path.windows(2)
+                    .map(|link| (link[0].min(link[1]), link[0].max(link[1])))
+            })
+            .collect()
+    }
+
+    /// Count a packet received along the hops
+    pub fn record_traffic(&mut self, hops: &[NodeId]) {
+        for node in hops {
+            self.stats.entry(*node).or_default().packets += 1;
+        }
+    }
+
+    /// Count an ack received along the hops
+    pub fn record_ack(&mut self, hops: &[NodeId]) {
+        for node in hops {
+            self.stats.entry(*node).or_default().acks += 1;
+        }
+    }
+
+    /// Count a nack caused by the node
+    pub fn record_nack(&mut self, node: NodeId) {
+        self.stats.entry(node).or_default().nacks += 1;
+    }
+
+    pub fn node_stats(&self) -> &BTreeMap<NodeId, NodeStats> {
+        &self.stats
+    }
+}
@@ -1,122 +1,159 @@
-use crate::ClientAudio;
-use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use crate::client::task::ClientTask;
+use crate::database::playlist_segment_count;
+use crate::error::ClientError;
+use crate::{ClientAudio, HistoryStats, Status};
 use packet_forge::SongMetaData;
-use rocket::response::status::NotFound;
+use params::{SegmentParam, SongId};
+use rocket::response::stream::{Event, EventStream};
 use rocket::serde::json::Json;
 use std::time::Duration;
-use rocket::State;
+use rocket::{Shutdown, State};
+use tokio::sync::broadcast::error::RecvError;
+mod network;
+mod params;
+
+pub use network::{network_graph, network_neighbours, network_paths, network_servers, network_stats};
 
 /// Get the song payload from the network
 /// 
-/// It first checks if the song is in the database.
+/// It first checks if the song is in the database, either as a local file or in the segment cache,
+/// where the segments prefetched in background are stored as well.
 /// If it is not in the database, it sends a peer list request to the server and waits for the response.
 /// The server answers with the node that has the song and than the client sends a request to the node.
 /// 
-/// When the request is sent the thread waits to receive the response from the other thread with a crossbeam channel.
+/// The request is posted to the processing thread and the endpoint awaits the response on a oneshot channel,
+/// so no thread of the rocket runtime is blocked while waiting for the network or for the client state.
+/// Concurrent requests for the same segment are coalesced in a single network request.
+/// If the peer does not answer in time, the request is retried on the next peer of the song.
+///
+/// The error status tells the frontend why the segment is missing: 400 if the url is malformed, 404 if no peer has the song,
+/// 503 if the network cannot be reached or the client is shutting down, 504 if the network did not answer in time.
 #[get("/audio/<id>/<segment>")]
 pub async fn get_song(
     client: &State<ClientAudio>,
-    id: &str,
-    segment: &str,
-) -> Result<Vec<u8>, NotFound<String>> {
-    let state = client.state.clone();
-
-    let (sender, receiver): (Sender<bool>, Receiver<bool>) = unbounded();
-    let id: u16 = id.parse().unwrap();
-    let mut segment_id: u32 = 0;
-    if !segment.ends_with(".m3u8") {
-        segment_id = segment
-            .trim_end_matches(".ts")
-            .replace("segment", "")
-            .parse::<u32>()
+    id: Result<SongId, ClientError>,
+    segment: Result<SegmentParam, ClientError>,
+) -> Result<Vec<u8>, ClientError> {
+    // malformed urls are answered with 400
+    let SongId(id) = id?;
+    let segment_id = segment?.segment_id();
+
+    // the database is read without waiting for the packet processing
+    let stored = client.db.get_segment(id, segment_id);
+
+    // a playlist read from the database tells how many segments can be prefetched,
+    // the ones received from the network are counted by the processing thread
+    if let (0, Ok(playlist)) = (segment_id, &stored) {
+        client
+            .downloads
+            .lock()
             .unwrap()
-            + 1;
+            .set_segment_count(id, playlist_segment_count(playlist));
     }
 
-    let read_state = state.read().unwrap();
-    match read_state.db.get_song_segment(id, segment_id) {
+    // request in background the segments that will be played next
+    client.prefetch(id, segment_id);
+
+    match stored {
         Ok(payload) => Ok(payload),
         Err(e) => {
-            //drop read_state to avoid deadlock
-            drop(read_state);
-
-            state
-                .read()
-                .unwrap()
+            client
                 .logger
                 .log_info(&format!("ask network for segment: db: {}", e));
 
-            // If the segmenent is not found, send request to server
-            state
-                .write()
-                .unwrap()
-                .inner_senders
-                .insert((id, segment_id), sender.clone());
+            // the processing thread is stopped, nobody would answer the request
+            if client.status() == Status::Terminated {
+                return Err(ClientError::Terminated);
+            }
+
+            // If the segmenent is not found, register the request. Concurrent requests for the
+            // same segment share a single network request and all receive the response.
+            let key = (id, segment_id);
+            let (waiter_id, mut receiver, first) = client.inflight.lock().unwrap().register(key);
 
             //send request to node
-            let mut client_mut = client.inner().clone();
-            client_mut.send_segment_request(id, segment_id as u32);
-
-            //waiting for response from the other thread
-            match receiver.recv_timeout(Duration::from_secs(10)) {
-                Ok(res) => {
-                    // check the result
-                    if res {
-                        let song_map = state.read().unwrap().song_map.clone();
-
-                        let playlist = song_map
-                            .get(&(id, segment_id))
-                            .unwrap()
-                            .clone();  
-
-                        // remove the song from the map as it is cached in the frontend
-                        state.write().unwrap().song_map.remove(&(id, segment_id));
-
-                        Ok(playlist)
-                    } else {
-                        state
-                            .read()
-                            .unwrap()
+            if first {
+                let task = ClientTask::Segment {
+                    file_id: id,
+                    segment: segment_id,
+                };
+                if let Err(e) = client.post(task) {
+                    client.inflight.lock().unwrap().cancel(key, waiter_id);
+                    return Err(e);
+                }
+            }
+
+            // on timeout the processing thread retries with the next peer and fails the request
+            // when every peer has been tried. Only the waiter driving the request asks for the retry,
+            // the others keep waiting for its result.
+            let mut attempt = 0;
+            loop {
+                //waiting for response from the other thread
+                let timeout = client.config.segment_timeout_secs;
+                match tokio::time::timeout(Duration::from_secs(timeout), &mut receiver).await {
+                    Ok(Ok(Ok(payload))) => return Ok(payload),
+                    Ok(Ok(Err(e))) => {
+                        client
                             .logger
-                            .log_error("Song not in the network");
-                        Err(NotFound("Song not in the network".to_string()))
+                            .log_error(&format!("Song not in the network: {}", e));
+                        return Err(e);
+                    }
+                    Err(_elapsed) => {
+                        // the client shut down after the request was registered
+                        if client.status() == Status::Terminated {
+                            client.inflight.lock().unwrap().cancel(key, waiter_id);
+                            return Err(ClientError::Terminated);
+                        }
+                        if !client.inflight.lock().unwrap().is_driver(key, waiter_id) {
+                            continue;
+                        }
+
+                        attempt += 1;
+                        let task = ClientTask::Retry {
+                            file_id: id,
+                            segment: segment_id,
+                            attempt,
+                        };
+                        if let Err(e) = client.post(task) {
+                            client.inflight.lock().unwrap().cancel(key, waiter_id);
+                            return Err(e);
+                        }
+                    }
+                    Ok(Err(_closed)) => {
+                        client.inflight.lock().unwrap().cancel(key, waiter_id);
+                        client.logger.log_error("Channel disconnected");
+                        return Err(ClientError::Send("Channel disconnected".to_string()));
                     }
-                }
-                Err(RecvTimeoutError::Timeout) => {
-                    state
-                        .read()
-                        .unwrap()
-                        .logger
-                        .log_error("Timeout while waiting for song");
-                    Err(NotFound("Timeout while waiting for song".to_string()))
-                }
-                Err(RecvTimeoutError::Disconnected) => {
-                    state
-                        .read()
-                        .unwrap()
-                        .logger
-                        .log_error("Channel disconnected");
-                    Err(NotFound("Channel disconnected".to_string()))
                 }
             }
         }
     }
 }
 
+/// Get the number of segments that are waiting for a response from the network
+#[get("/pending-requests")]
+pub async fn pending_requests(client: &State<ClientAudio>) -> Json<usize> {
+    let res = client.inflight.lock().unwrap().pending_count();
+    Json(res)
+}
+
+/// Get the counters of the fragments waiting for an ack, for debugging
+#[get("/history-stats")]
+pub async fn history_stats(client: &State<ClientAudio>) -> Json<HistoryStats> {
+    Json(client.history_stats())
+}
+
 /// Get the song metadata that is syncronized with the network
 #[get("/audio-files")]
-pub async fn audio_files(client: &State<ClientAudio>) -> Json<Vec<SongMetaData>> {
-    let state = client.state.clone();
-    let res = state.read().unwrap().db.get_all_songs_meta();
+pub async fn audio_files(client: &State<ClientAudio>) -> Result<Json<Vec<SongMetaData>>, ClientError> {
+    let res = client.db.get_all_songs_meta();
     match res {
-        Ok(songs) => Json(songs),
+        Ok(songs) => Ok(Json(songs)),
         Err(e) => {
-            state
-                .read()
-                .unwrap()
+            client
                 .logger
                 .log_error(&format!("Error audio_files endpoint: {}", e));
-            Json(Vec::new())
+            Err(e)
         }
     }
 }
@@ -124,17 +161,32 @@ pub async fn audio_files(client: &State<ClientAudio>) -> Json<Vec<SongMetaData>>
 /// Check if the client is ready to stream audio
 #[get("/is-ready")]
 pub async fn is_ready(client: &State<ClientAudio>) -> Json<bool> {
-    let state = client.state.clone();
-    println!("is_ready???? {:?}", state.read().unwrap().status);
-    let res = state.read().unwrap().status == crate::Status::Running;
-    print!("is_ready???? {:?}", res);
-    Json(res)
+    Json(client.status() == Status::Running)
 }
 
 /// Get the client id
 #[get("/get-id")]
 pub async fn get_id(client: &State<ClientAudio>) -> Json<u8> {
-    let state = client.state.clone();
-    let res = state.read().unwrap().id;
-    Json(res)
+    Json(client.id)
+}
+
+/// Stream the progress of the transfers as server-sent events, one json `ProgressEvent` per event.
+/// The stream ends when the client or the rocket server shut down.
+#[get("/progress")]
+pub fn progress(client: &State<ClientAudio>, mut shutdown: Shutdown) -> EventStream![] {
+    let mut receiver = client.progress.subscribe();
+    EventStream! {
+        loop {
+            let event = rocket::tokio::select! {
+                event = receiver.recv() => match event {
+                    Ok(event) => event,
+                    Err(RecvError::Closed) => break,
+                    // the events lost by a slow frontend are skipped
+                    Err(RecvError::Lagged(_)) => continue,
+                },
+                _ = &mut shutdown => break,
+            };
+            yield Event::json(&event);
+        }
+    }
 }
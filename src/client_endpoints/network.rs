@@ -0,0 +1,62 @@
+use crate::client::task::ClientTask;
+use crate::error::ClientError;
+use crate::{ClientAudio, NetworkGraph, NetworkPath, NetworkReport, NetworkServers, NodeStats};
+use rocket::serde::json::Json;
+use rocket::State;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use wg_internal::network::NodeId;
+
+/// Ask the processing thread for the network known by the client, the endpoint awaits the answer
+/// without blocking the rocket runtime
+async fn network_report(client: &ClientAudio) -> Result<NetworkReport, ClientError> {
+    let (reply, receiver) = oneshot::channel();
+    client.post(ClientTask::Network(reply))?;
+
+    let timeout = Duration::from_secs(client.config.segment_timeout_secs);
+    match tokio::time::timeout(timeout, receiver).await {
+        Ok(Ok(report)) => Ok(report),
+        // the processing thread stopped before answering
+        Ok(Err(_closed)) => Err(ClientError::Terminated),
+        Err(_elapsed) => Err(ClientError::Timeout),
+    }
+}
+
+/// Get the nodes and edges of the routing graph
+#[get("/network/graph")]
+pub async fn network_graph(client: &State<ClientAudio>) -> Result<Json<NetworkGraph>, ClientError> {
+    Ok(Json(network_report(client).await?.graph))
+}
+
+/// Get the discovered servers
+#[get("/network/servers")]
+pub async fn network_servers(
+    client: &State<ClientAudio>,
+) -> Result<Json<NetworkServers>, ClientError> {
+    Ok(Json(network_report(client).await?.servers))
+}
+
+/// Get the drones directly connected to the client
+#[get("/network/neighbours")]
+pub async fn network_neighbours(
+    client: &State<ClientAudio>,
+) -> Result<Json<Vec<NodeId>>, ClientError> {
+    Ok(Json(network_report(client).await?.neighbours))
+}
+
+/// Get the current best path to every server and to every known peer
+#[get("/network/paths")]
+pub async fn network_paths(
+    client: &State<ClientAudio>,
+) -> Result<Json<Vec<NetworkPath>>, ClientError> {
+    Ok(Json(network_report(client).await?.paths))
+}
+
+/// Get the packets, acks and nacks seen by the client for every node
+#[get("/network/stats")]
+pub async fn network_stats(
+    client: &State<ClientAudio>,
+) -> Result<Json<BTreeMap<NodeId, NodeStats>>, ClientError> {
+    Ok(Json(network_report(client).await?.stats))
+}
@@ -0,0 +1,52 @@
+use crate::database::parse_segment_file_name;
+use crate::error::ClientError;
+use rocket::request::FromParam;
+
+/// Song id of the `/audio/<id>/<segment>` route
+pub struct SongId(pub u16);
+
+impl<'a> FromParam<'a> for SongId {
+    type Error = ClientError;
+
+    fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+        param
+            .parse::<u16>()
+            .map(SongId)
+            .map_err(|_| ClientError::BadRequest(format!("Invalid song id: {}", param)))
+    }
+}
+
+/// Segment of the `/audio/<id>/<segment>` route: the HLS playlist or a numbered `segment<N>.ts` file
+pub enum SegmentParam {
+    Playlist,
+    Segment(u32),
+}
+
+impl SegmentParam {
+    /// Index of the segment in the database, the playlist is stored at index 0
+    pub fn segment_id(&self) -> u32 {
+        match self {
+            SegmentParam::Playlist => 0,
+            SegmentParam::Segment(segment) => segment + 1,
+        }
+    }
+}
+
+impl<'a> FromParam<'a> for SegmentParam {
+    type Error = ClientError;
+
+    fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+        if param.len() > ".m3u8".len() && param.ends_with(".m3u8") {
+            return Ok(SegmentParam::Playlist);
+        }
+
+        match parse_segment_file_name(param) {
+            // the segment index is shifted by one in the database
+            Some(segment) if segment < u32::MAX => Ok(SegmentParam::Segment(segment)),
+            _ => Err(ClientError::BadRequest(format!(
+                "Invalid segment name: {}",
+                param
+            ))),
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use crate::client::downloads::{DEFAULT_PREFETCH_WINDOW, MAX_PREFETCH_WINDOW};
+use crate::client::packet_history::{
+    DEFAULT_HISTORY_MAX_AGE_SECS, DEFAULT_HISTORY_MAX_BYTES, MIN_HISTORY_MAX_BYTES,
+};
+use crate::database::DEFAULT_CACHE_BUDGET;
+use crate::error::{ClientError, ClientResult};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+use wg_internal::network::NodeId;
+
+/// Name of the optional settings file, looked up in the directory passed to `run`
+pub const CONFIG_FILE_NAME: &str = "client_config.json";
+
+/// Settings of the client. Every field is optional in the settings file and falls back to its default.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    /// Port of the rocket server, defaults to `8000 + id`
+    pub http_port: Option<u16>,
+    /// Path of the database, defaults to `db/client_audio/client-{id}`
+    pub db_path: Option<String>,
+    /// Seconds the endpoint waits for a segment before failing over to the next peer
+    pub segment_timeout_secs: u64,
+    /// Seconds between two file list requests
+    pub filelist_refresh_secs: u64,
+    /// Seconds between two periodic flood requests, up to 4 times longer while the topology is stable
+    pub flood_refresh_secs: u64,
+    /// Bytes the cache of remotely fetched segments is allowed to hold
+    pub cache_budget_bytes: u64,
+    /// Number of segments requested ahead of the one played by the browser, they are kept in the segment cache
+    pub prefetch_window: u32,
+    /// Seconds after which a fragment that has never been acked is given up
+    pub history_max_age_secs: u64,
+    /// Bytes the fragments waiting for an ack are allowed to hold
+    pub history_max_bytes: u64,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            http_port: None,
+            db_path: None,
+            segment_timeout_secs: 10,
+            filelist_refresh_secs: 20,
+            flood_refresh_secs: 60,
+            cache_budget_bytes: DEFAULT_CACHE_BUDGET,
+            prefetch_window: DEFAULT_PREFETCH_WINDOW,
+            history_max_age_secs: DEFAULT_HISTORY_MAX_AGE_SECS,
+            history_max_bytes: DEFAULT_HISTORY_MAX_BYTES,
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Load the settings file from the client directory. If the file does not exist the default settings are used.
+    pub fn load(init_client_path: &str) -> ClientResult<Self> {
+        let path = Path::new(init_client_path).join(CONFIG_FILE_NAME);
+        if !path.exists() {
+            return Ok(ClientConfig::default());
+        }
+
+        let content = fs::read_to_string(&path).map_err(|e| {
+            ClientError::InvalidData(format!(
+                "Error reading config file {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        let config: ClientConfig = serde_json::from_str(&content).map_err(|e| {
+            ClientError::InvalidData(format!(
+                "Error parsing config file {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check that the values are usable
+    pub fn validate(&self) -> ClientResult<()> {
+        if self.http_port == Some(0) {
+            return Err(ClientError::InvalidData(
+                "Invalid config: http_port must not be 0".to_string(),
+            ));
+        }
+        if self.db_path.as_deref().is_some_and(str::is_empty) {
+            return Err(ClientError::InvalidData(
+                "Invalid config: db_path must not be empty".to_string(),
+            ));
+        }
+        if self.segment_timeout_secs == 0 || self.segment_timeout_secs > 300 {
+            return Err(ClientError::InvalidData(
+                "Invalid config: segment_timeout_secs must be between 1 and 300".to_string(),
+            ));
+        }
+        if self.filelist_refresh_secs == 0 {
+            return Err(ClientError::InvalidData(
+                "Invalid config: filelist_refresh_secs must be greater than 0".to_string(),
+            ));
+        }
+        if self.flood_refresh_secs == 0 {
+            return Err(ClientError::InvalidData(
+                "Invalid config: flood_refresh_secs must be greater than 0".to_string(),
+            ));
+        }
+        if self.prefetch_window > MAX_PREFETCH_WINDOW {
+            return Err(ClientError::InvalidData(format!(
+                "Invalid config: prefetch_window must be at most {}",
+                MAX_PREFETCH_WINDOW
+            )));
+        }
+        if self.history_max_age_secs == 0 {
+            return Err(ClientError::InvalidData(
+                "Invalid config: history_max_age_secs must be greater than 0".to_string(),
+            ));
+        }
+        if self.history_max_bytes < MIN_HISTORY_MAX_BYTES {
+            return Err(ClientError::InvalidData(format!(
+                "Invalid config: history_max_bytes must be at least {}",
+                MIN_HISTORY_MAX_BYTES
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn http_port(&self, id: NodeId) -> u16 {
+        self.http_port.unwrap_or(8000 + u16::from(id))
+    }
+
+    pub fn db_path(&self, id: NodeId) -> String {
+        self.db_path
+            .clone()
+            .unwrap_or_else(|| Self::default_db_path(id))
+    }
+
+    pub fn default_db_path(id: NodeId) -> String {
+        format!("db/client_audio/client-{}", id)
+    }
+}
@@ -1,97 +1,200 @@
+use crate::config::CONFIG_FILE_NAME;
+use crate::error::{ClientError, ClientResult};
 use packet_forge::{Metadata, SongMetaData};
 use sled;
 use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+/// Default number of bytes the segment cache is allowed to hold
+pub const DEFAULT_CACHE_BUDGET: u64 = 128 * 1024 * 1024;
 
 pub struct AudioDatabase {
+    // opened once the settings of the client are known
+    store: OnceLock<Store>,
+    cache_budget: AtomicU64,
+    cache_size: AtomicU64,
+    access_tick: AtomicU64,
+}
+
+struct Store {
     db: sled::Db,
+    // segments fetched from other peers, key is the same as the song payload
+    cache: sled::Tree,
+    // segment key -> last access tick
+    cache_access: sled::Tree,
+    // last access tick -> segment key, ordered so that the first entry is the least recently used
+    cache_lru: sled::Tree,
 }
 
 impl AudioDatabase {
-    pub fn new(database: &str) -> Self {
-        let db = match sled::open(database) {
-            Ok(db) => AudioDatabase { db },
-            Err(e) => {
-                eprintln!("Error opening database: {}", e);
-                std::process::exit(1);
-            }
+    /// Create the database without touching the disk, `open` must be called before using it
+    pub fn new(cache_budget: u64) -> Self {
+        AudioDatabase {
+            store: OnceLock::new(),
+            cache_budget: AtomicU64::new(cache_budget),
+            cache_size: AtomicU64::new(0),
+            access_tick: AtomicU64::new(0),
+        }
+    }
+
+    /// Open the database at the given path. The database can be opened only once.
+    pub fn open(&self, database: &str) -> ClientResult<()> {
+        let db = sled::open(database)
+            .map_err(|e| ClientError::Database(format!("Error opening database: {}", e)))?;
+
+        let open_tree = |name: &str| {
+            db.open_tree(name).map_err(|e| {
+                ClientError::Database(format!("Error opening database tree {}: {}", name, e))
+            })
+        };
+        let cache = open_tree("segment_cache")?;
+        let cache_access = open_tree("segment_cache_access")?;
+        let cache_lru = open_tree("segment_cache_lru")?;
+
+        // Restore the cache size and the access clock from the previous run
+        let cache_size = cache
+            .iter()
+            .values()
+            .filter_map(Result::ok)
+            .map(|value| value.len() as u64)
+            .sum();
+        let access_tick = match cache_lru.last() {
+            Ok(Some((tick, _))) => tick_from_bytes(&tick) + 1,
+            _ => 0,
         };
 
-        db
+        let store = Store {
+            db,
+            cache,
+            cache_access,
+            cache_lru,
+        };
+        if self.store.set(store).is_err() {
+            return Err(ClientError::Database(
+                "Database has already been opened".to_string(),
+            ));
+        }
+        self.cache_size.store(cache_size, Ordering::SeqCst);
+        self.access_tick.store(access_tick, Ordering::SeqCst);
+
+        // The budget may have been reduced since the last run
+        self.evict_cached_segments().map(|_| ())
+    }
+
+    fn store(&self) -> ClientResult<&Store> {
+        self.store
+            .get()
+            .ok_or_else(|| ClientError::Database("Database not opened".to_string()))
     }
 
-    /// Initialize the database by clearing it and inserting local files
-    pub fn init(&self, local_path: &str) -> Result<(), String> {
+    /// Initialize the database by clearing it and inserting local files.
+    /// The segment cache is stored in a separate tree and is kept between runs.
+    pub fn init(&self, local_path: &str) -> ClientResult<()> {
         // Clear the database
-        self.db
+        let store = self.store()?;
+        store
+            .db
             .clear()
-            .map_err(|e| format!("Error clearing database: {}", e))?;
-        self.db
+            .map_err(|e| ClientError::Database(format!("Error clearing database: {}", e)))?;
+        store
+            .db
             .flush()
-            .map_err(|e| format!("Error flushing database: {}", e))?;
+            .map_err(|e| ClientError::Database(format!("Error flushing database: {}", e)))?;
 
-        let dir_entries = fs::read_dir(local_path)
-            .map_err(|e| format!("Error reading directory {}: {}", local_path, e))?;
+        let dir_entries = fs::read_dir(local_path).map_err(|e| {
+            ClientError::InvalidData(format!("Error reading directory {}: {}", local_path, e))
+        })?;
 
         // Find the JSON file in the directory
         let mut json_file_path = None;
         for entry in dir_entries {
-            let entry = entry.map_err(|e| format!("Error reading directory entry: {}", e))?;
+            let entry = entry.map_err(|e| {
+                ClientError::InvalidData(format!("Error reading directory entry: {}", e))
+            })?;
             let path = entry.path();
+            // the settings file lives in the same directory but is not song metadata
+            if path.file_name().and_then(|name| name.to_str()) == Some(CONFIG_FILE_NAME) {
+                continue;
+            }
             if path.is_file() && path.extension().and_then(|ext| ext.to_str()) == Some("json") {
                 if json_file_path.is_some() {
-                    return Err(
-                        "Error: More than one .json file found in the directory".to_string()
-                    );
+                    return Err(ClientError::InvalidData(
+                        "More than one .json file found in the directory".to_string(),
+                    ));
                 }
                 json_file_path = Some(path);
             }
         }
 
         let json_file_path = json_file_path
-            .ok_or_else(|| format!("Error: No .json file found in directory {}", local_path))?;
+            .ok_or_else(|| {
+                ClientError::InvalidData(format!("No .json file found in directory {}", local_path))
+            })?;
 
         let file_content = fs::read_to_string(&json_file_path).map_err(|e| {
-            format!(
+            ClientError::InvalidData(format!(
                 "Error reading metadata file {}: {}",
                 json_file_path.display(),
                 e
-            )
+            ))
         })?;
 
         let json_data: serde_json::Value = serde_json::from_str(&file_content)
-            .map_err(|e| format!("Error parsing JSON metadata: {}", e))?;
+            .map_err(|e| ClientError::InvalidData(format!("Error parsing JSON metadata: {}", e)))?;
 
         let songs_array = json_data["songs"]
             .as_array()
-            .ok_or_else(|| "Error JSON metadata is not valid".to_string())?;
+            .ok_or_else(|| ClientError::InvalidData("JSON metadata is not valid".to_string()))?;
 
         // Insert the songs into the database
         for song in songs_array {
             let song: SongMetaData = serde_json::from_value(song.clone())
-                .map_err(|e| format!("Error deserializing song: {}", e))?;
+                .map_err(|e| ClientError::InvalidData(format!("Error deserializing song: {}", e)))?;
 
             let song_title_parsed = song.title.replace(" ", "").to_lowercase();
             let song_id = self.insert_song_meta(song)?;
 
             let song_entries = fs::read_dir(format!("{}/songs/{}", local_path, song_title_parsed))
-                .map_err(|e| format!("Error reading directory {}: {}", local_path, e))?;
+                .map_err(|e| {
+                    ClientError::InvalidData(format!("Error reading directory {}: {}", local_path, e))
+                })?;
 
             for entry in song_entries {
-                let entry = entry.map_err(|e| format!("Error reading directory entry: {}", e))?;
+                let entry = entry.map_err(|e| {
+                    ClientError::InvalidData(format!("Error reading directory entry: {}", e))
+                })?;
                 let path = entry.path();
                 if path.is_file() {
                     let entry_content = fs::read(&path).map_err(|e| {
-                        format!("Error reading segment file {}: {}", path.display(), e)
+                        ClientError::InvalidData(format!(
+                            "Error reading segment file {}: {}",
+                            path.display(),
+                            e
+                        ))
                     })?;
 
                     // Push the payload to the database
                     if path.extension().and_then(|ext| ext.to_str()) == Some("m3u8") {
                         self.insert_song_segment(song_id.clone(), 0,entry_content)?;
                     } else if path.extension().and_then(|ext| ext.to_str()) == Some("ts") {
-                        let segment: u32 = path.file_stem().unwrap().to_string_lossy().to_string().replace("segment", "").parse::<u32>().unwrap();
-                        self.insert_song_segment(song_id.clone(), segment+1, entry_content)?;
+                        let segment = path
+                            .file_name()
+                            .and_then(|name| name.to_str())
+                            .and_then(parse_segment_file_name)
+                            .filter(|segment| *segment < u32::MAX)
+                            .ok_or_else(|| {
+                                ClientError::InvalidData(format!(
+                                    "Invalid segment file name {}",
+                                    path.display()
+                                ))
+                            })?;
+                        self.insert_song_segment(song_id.clone(), segment + 1, entry_content)?;
                     } else {
-                        return Err("Error: Invalid file extension".to_string());
+                        return Err(ClientError::InvalidData(format!(
+                            "Invalid file extension {}",
+                            path.display()
+                        )));
                     }
                 }
             }
@@ -101,15 +204,16 @@ impl AudioDatabase {
 
     /// Insert the song metadata into the database and return the song ID
     /// If the id of the passed song is 0, a new id is generated
-    pub fn insert_song_meta(&self, mut song: SongMetaData) -> Result<u16, String> {
+    pub fn insert_song_meta(&self, mut song: SongMetaData) -> ClientResult<u16> {
         if song.id == 0 {
             song.id = song.compact_hash_u16();
         }
 
-        let serialized_song = bincode::serialize(&song).unwrap();
-        match self.db.insert(song.id.to_be_bytes(), serialized_song) {
+        let serialized_song = bincode::serialize(&song)
+            .map_err(|e| ClientError::Database(format!("Error serializing song: {}", e)))?;
+        match self.store()?.db.insert(song.id.to_be_bytes(), serialized_song) {
             Ok(_) => Ok(song.id),
-            Err(e) => Err(format!("Error inserting song: {}", e)),
+            Err(e) => Err(ClientError::Database(format!("Error inserting song: {}", e))),
         }
     }
 
@@ -119,57 +223,331 @@ impl AudioDatabase {
         id: u16,
         segment: u32,
         payload: Vec<u8>,
-    ) -> Result<(), String> {
-        let mut key: Vec<u8> = Vec::new();
-        key.extend_from_slice(&segment.to_be_bytes());
-        key.extend_from_slice(&id.to_be_bytes());
-        match self.db.insert(key, payload) {
+    ) -> ClientResult<()> {
+        match self.store()?.db.insert(segment_key(id, segment), payload) {
             Ok(_) => Ok(()),
-            Err(e) => Err(format!("Error inserting song payload: {}", e)),
+            Err(e) => Err(ClientError::Database(format!(
+                "Error inserting song payload: {}",
+                e
+            ))),
         }
     }
 
     /// Get the song metadata from the database
-    pub fn get_song_meta(&self, id: u16) -> Result<SongMetaData, String> {
-        match self.db.get(id.to_be_bytes()) {
+    pub fn get_song_meta(&self, id: u16) -> ClientResult<SongMetaData> {
+        match self.store()?.db.get(id.to_be_bytes()) {
             Ok(Some(data)) => {
-                let song: SongMetaData = bincode::deserialize(&data).unwrap();
+                let song: SongMetaData = bincode::deserialize(&data).map_err(|e| {
+                    ClientError::Database(format!("Error deserializing song: {}", e))
+                })?;
                 Ok(song)
             }
-            Ok(None) => Err("Song not found".to_string()),
-            Err(e) => Err(format!("Error getting song: {}", e)),
+            Ok(None) => Err(ClientError::NotFound("Song not found".to_string())),
+            Err(e) => Err(ClientError::Database(format!("Error getting song: {}", e))),
         }
     }
 
     /// Get the song payload from the database
-    pub fn get_song_segment(&self, id: u16, segment: u32) -> Result<Vec<u8>, String> {
-        let mut key: Vec<u8> = Vec::new();
-        key.extend_from_slice(&segment.to_be_bytes());
-        key.extend_from_slice(&id.to_be_bytes());
-        match self.db.get(key) {
+    pub fn get_song_segment(&self, id: u16, segment: u32) -> ClientResult<Vec<u8>> {
+        match self.store()?.db.get(segment_key(id, segment)) {
             Ok(Some(data)) => Ok(data.to_vec()),
-            Ok(None) => Err("Song payload not found".to_string()),
-            Err(e) => Err(format!("Error getting song payload: {}", e)),
+            Ok(None) => Err(ClientError::NotFound("Song payload not found".to_string())),
+            Err(e) => Err(ClientError::Database(format!(
+                "Error getting song payload: {}",
+                e
+            ))),
         }
     }
 
     /// Get all the songs metadata from the database
-    pub fn get_all_songs_meta(&self) -> Result<Vec<SongMetaData>, String> {
+    pub fn get_all_songs_meta(&self) -> ClientResult<Vec<SongMetaData>> {
         let mut songs = Vec::new();
 
-        for record in self.db.iter() {
+        for record in self.store()?.db.iter() {
             match record {
                 Ok((key, data)) => {
                     if key.len() == 2 {
                         match bincode::deserialize(&data) {
                             Ok(song) => songs.push(song),
-                            Err(e) => return Err(format!("Error deserializing song: {}", e)),
+                            Err(e) => {
+                                return Err(ClientError::Database(format!(
+                                    "Error deserializing song: {}",
+                                    e
+                                )))
+                            }
                         }
                     }
                 }
-                Err(e) => return Err(format!("Error iterating database: {}", e)),
+                Err(e) => {
+                    return Err(ClientError::Database(format!(
+                        "Error iterating database: {}",
+                        e
+                    )))
+                }
+            }
+        }
+        Ok(songs)
+    }
+
+    /// Get the metadata of the songs that are stored as local files
+    pub fn get_local_songs_meta(&self) -> ClientResult<Vec<SongMetaData>> {
+        let store = self.store()?;
+        let songs = self.get_all_songs_meta()?;
+        let mut local = Vec::new();
+        for song in songs {
+            let has_playlist = store
+                .db
+                .contains_key(segment_key(song.id, 0))
+                .map_err(|e| ClientError::Database(format!("Error getting song payload: {}", e)))?;
+            if has_playlist {
+                local.push(song);
+            }
+        }
+        Ok(local)
+    }
+
+    /// Get the metadata of the songs that can be served to other peers:
+    /// the local files and the remote songs that are fully cached
+    pub fn get_seedable_songs_meta(&self) -> ClientResult<Vec<SongMetaData>> {
+        let mut songs = self.get_local_songs_meta()?;
+        for song in self.get_all_songs_meta()? {
+            if !songs.iter().any(|local| local.id == song.id) && self.is_song_cached(song.id)? {
+                songs.push(song);
             }
         }
         Ok(songs)
     }
+
+    /// Check if the song can be served to other peers: its metadata is known and it is either
+    /// a local file or fully cached
+    pub fn is_song_seedable(&self, id: u16) -> ClientResult<bool> {
+        let store = self.store()?;
+        let contains = |key: Vec<u8>| {
+            store
+                .db
+                .contains_key(key)
+                .map_err(|e| ClientError::Database(format!("Error getting song: {}", e)))
+        };
+        if !contains(id.to_be_bytes().to_vec())? {
+            return Ok(false);
+        }
+        if contains(segment_key(id, 0))? {
+            return Ok(true);
+        }
+        self.is_song_cached(id)
+    }
+
+    /// Check if the playlist and all the segments of a remote song are in the cache
+    pub fn is_song_cached(&self, id: u16) -> ClientResult<bool> {
+        let store = self.store()?;
+        let playlist = match store.cache.get(segment_key(id, 0)) {
+            Ok(Some(playlist)) => playlist,
+            Ok(None) => return Ok(false),
+            Err(e) => {
+                return Err(ClientError::Database(format!(
+                    "Error getting cached segment: {}",
+                    e
+                )))
+            }
+        };
+
+        for segment in 1..=playlist_segment_count(&playlist) {
+            let cached = store
+                .cache
+                .contains_key(segment_key(id, segment))
+                .map_err(|e| {
+                    ClientError::Database(format!("Error getting cached segment: {}", e))
+                })?;
+            if !cached {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Get the song payload from the local files or, if missing, from the segment cache
+    pub fn get_segment(&self, id: u16, segment: u32) -> ClientResult<Vec<u8>> {
+        match self.get_song_segment(id, segment) {
+            Ok(payload) => Ok(payload),
+            Err(_) => self.get_cached_segment(id, segment),
+        }
+    }
+
+    /// Check if the segment is in the local files or in the segment cache, without marking it as recently used
+    pub fn contains_segment(&self, id: u16, segment: u32) -> ClientResult<bool> {
+        let store = self.store()?;
+        let key = segment_key(id, segment);
+        let contains = |tree: &sled::Tree| {
+            tree.contains_key(&key)
+                .map_err(|e| ClientError::Database(format!("Error checking segment: {}", e)))
+        };
+        Ok(contains(&store.db)? || contains(&store.cache)?)
+    }
+
+    /// Get a segment fetched from another peer and mark it as recently used
+    pub fn get_cached_segment(&self, id: u16, segment: u32) -> ClientResult<Vec<u8>> {
+        let key = segment_key(id, segment);
+        match self.store()?.cache.get(&key) {
+            Ok(Some(data)) => {
+                self.touch_cached_segment(&key)?;
+                Ok(data.to_vec())
+            }
+            Ok(None) => Err(ClientError::NotFound("Segment not cached".to_string())),
+            Err(e) => Err(ClientError::Database(format!(
+                "Error getting cached segment: {}",
+                e
+            ))),
+        }
+    }
+
+    /// Insert a segment fetched from another peer in the cache, evicting the least recently used
+    /// segments if the cache exceeds its budget. Segments bigger than the whole budget are not cached.
+    /// Returns the songs that lost a segment to make room.
+    pub fn insert_cached_segment(
+        &self,
+        id: u16,
+        segment: u32,
+        payload: Vec<u8>,
+    ) -> ClientResult<Vec<u16>> {
+        let size = payload.len() as u64;
+        if size > self.cache_budget.load(Ordering::SeqCst) {
+            return Ok(Vec::new());
+        }
+
+        let key = segment_key(id, segment);
+        let previous = self
+            .store()?
+            .cache
+            .insert(&key, payload)
+            .map_err(|e| ClientError::Database(format!("Error inserting cached segment: {}", e)))?;
+        let previous_size = previous.map_or(0, |value| value.len() as u64);
+        self.cache_size.fetch_add(size, Ordering::SeqCst);
+        self.cache_size.fetch_sub(previous_size, Ordering::SeqCst);
+
+        self.touch_cached_segment(&key)?;
+        self.evict_cached_segments()
+    }
+
+    /// Change the cache budget, used before opening the database
+    pub fn set_cache_budget(&self, cache_budget: u64) {
+        self.cache_budget.store(cache_budget, Ordering::SeqCst);
+    }
+
+    /// Write to disk every pending change of the database and of the cache
+    pub fn flush(&self) -> ClientResult<()> {
+        // nothing has been written if the database has never been opened
+        let Some(store) = self.store.get() else {
+            return Ok(());
+        };
+        store
+            .db
+            .flush()
+            .map_err(|e| ClientError::Database(format!("Error flushing database: {}", e)))?;
+        Ok(())
+    }
+
+    /// Number of bytes currently held by the segment cache
+    pub fn cache_size(&self) -> u64 {
+        self.cache_size.load(Ordering::SeqCst)
+    }
+
+    /// Number of bytes the segment cache is allowed to hold
+    pub fn cache_budget(&self) -> u64 {
+        self.cache_budget.load(Ordering::SeqCst)
+    }
+
+    /// Number of segments currently held by the segment cache
+    pub fn cached_segments(&self) -> usize {
+        self.store.get().map_or(0, |store| store.cache.len())
+    }
+
+    /// Update the access tick of a cached segment
+    fn touch_cached_segment(&self, key: &[u8]) -> ClientResult<()> {
+        let store = self.store()?;
+        let tick = self.access_tick.fetch_add(1, Ordering::SeqCst);
+        let previous = store
+            .cache_access
+            .insert(key, tick.to_be_bytes().to_vec())
+            .map_err(|e| ClientError::Database(format!("Error updating cache access: {}", e)))?;
+        if let Some(previous) = previous {
+            store
+                .cache_lru
+                .remove(previous)
+                .map_err(|e| ClientError::Database(format!("Error updating cache access: {}", e)))?;
+        }
+        store
+            .cache_lru
+            .insert(tick.to_be_bytes(), key)
+            .map_err(|e| ClientError::Database(format!("Error updating cache access: {}", e)))?;
+        Ok(())
+    }
+
+    /// Remove the least recently used segments until the cache fits in its budget.
+    /// Returns the songs that lost a segment.
+    fn evict_cached_segments(&self) -> ClientResult<Vec<u16>> {
+        let store = self.store()?;
+        let mut songs = Vec::new();
+        while self.cache_size() > self.cache_budget.load(Ordering::SeqCst) {
+            let Some((_, key)) = store
+                .cache_lru
+                .pop_min()
+                .map_err(|e| ClientError::Database(format!("Error evicting cached segment: {}", e)))?
+            else {
+                break;
+            };
+            store
+                .cache_access
+                .remove(&key)
+                .map_err(|e| ClientError::Database(format!("Error evicting cached segment: {}", e)))?;
+            if let Some(value) = store
+                .cache
+                .remove(&key)
+                .map_err(|e| ClientError::Database(format!("Error evicting cached segment: {}", e)))?
+            {
+                self.cache_size
+                    .fetch_sub(value.len() as u64, Ordering::SeqCst);
+            }
+            let song = song_id_from_key(&key);
+            if !songs.contains(&song) {
+                songs.push(song);
+            }
+        }
+        Ok(songs)
+    }
+}
+
+/// Number of `.ts` segments listed in an HLS playlist
+pub fn playlist_segment_count(playlist: &[u8]) -> u32 {
+    String::from_utf8_lossy(playlist)
+        .lines()
+        .filter(|line| line.trim().ends_with(".ts"))
+        .count() as u32
+}
+
+/// Number of a segment file named `segment<N>.ts`, `None` if the name does not match
+pub fn parse_segment_file_name(file_name: &str) -> Option<u32> {
+    let number = file_name.strip_prefix("segment")?.strip_suffix(".ts")?;
+    if number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    number.parse::<u32>().ok()
+}
+
+/// Key of a song payload: the segment number followed by the song ID
+fn segment_key(id: u16, segment: u32) -> Vec<u8> {
+    let mut key: Vec<u8> = Vec::new();
+    key.extend_from_slice(&segment.to_be_bytes());
+    key.extend_from_slice(&id.to_be_bytes());
+    key
+}
+
+/// Song ID of a segment key
+fn song_id_from_key(key: &[u8]) -> u16 {
+    u16::from_be_bytes([key[4], key[5]])
+}
+
+fn tick_from_bytes(bytes: &[u8]) -> u64 {
+    let mut tick = [0u8; 8];
+    tick.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(tick)
 }
@@ -0,0 +1,93 @@
+use packet_forge::FileHash;
+use rocket::http::Status as HttpStatus;
+use rocket::response::{self, status, Responder};
+use rocket::Request;
+use std::fmt;
+use wg_internal::network::NodeId;
+
+/// Errors of the client, shared by the database, the message handler and the rocket endpoints
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientError {
+    /// The database failed to read or write
+    Database(String),
+    /// The request of the frontend is malformed
+    BadRequest(String),
+    /// The song or segment is not stored locally
+    NotFound(String),
+    /// The local files, the received data or the settings are not valid
+    InvalidData(String),
+    /// The routing graph has no path between the two nodes
+    NoRoute { from: NodeId, to: NodeId },
+    /// The node is not a neighbour of the client
+    NoNeighbour(NodeId),
+    /// No server has been discovered yet or none is reachable
+    NoServer,
+    /// No peer is known or reachable for the file
+    NoPeer(FileHash),
+    /// The network did not answer in time
+    Timeout,
+    /// The message could not be split in fragments
+    Disassembly(String),
+    /// The received fragments could not be assembled in a message
+    Assembly(String),
+    /// A channel towards a drone or the simulation controller is closed
+    Send(String),
+    /// The command of the simulation controller could not be applied
+    Command(String),
+    /// The client is shutting down
+    Terminated,
+}
+
+pub type ClientResult<T> = Result<T, ClientError>;
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Database(msg) => write!(f, "Database error: {}", msg),
+            ClientError::BadRequest(msg) => write!(f, "Bad request: {}", msg),
+            ClientError::NotFound(msg) => write!(f, "Not found: {}", msg),
+            ClientError::InvalidData(msg) => write!(f, "Invalid data: {}", msg),
+            ClientError::NoRoute { from, to } => {
+                write!(f, "No path found from {} to {}!", from, to)
+            }
+            ClientError::NoNeighbour(id) => write!(f, "No neigbour of ID [{}] found.", id),
+            ClientError::NoServer => write!(f, "No reachable server"),
+            ClientError::NoPeer(file) => write!(f, "No reachable peer for file {}", file),
+            ClientError::Timeout => write!(f, "Timeout while waiting for the network"),
+            ClientError::Disassembly(msg) => write!(f, "Error on message disassemble: {}", msg),
+            ClientError::Assembly(msg) => write!(f, "Error on fragments assemble: {}", msg),
+            ClientError::Send(msg) => write!(f, "Send error: {}", msg),
+            ClientError::Command(msg) => write!(f, "Command error: {}", msg),
+            ClientError::Terminated => write!(f, "The client is shutting down"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl ClientError {
+    /// HTTP status returned by the endpoints for this error
+    pub fn http_status(&self) -> HttpStatus {
+        match self {
+            ClientError::BadRequest(_) => HttpStatus::BadRequest,
+            ClientError::NotFound(_) | ClientError::NoPeer(_) => HttpStatus::NotFound,
+            ClientError::NoRoute { .. }
+            | ClientError::NoNeighbour(_)
+            | ClientError::NoServer
+            | ClientError::Send(_)
+            | ClientError::Terminated => HttpStatus::ServiceUnavailable,
+            ClientError::Timeout => HttpStatus::GatewayTimeout,
+            ClientError::Database(_)
+            | ClientError::InvalidData(_)
+            | ClientError::Disassembly(_)
+            | ClientError::Assembly(_)
+            | ClientError::Command(_) => HttpStatus::InternalServerError,
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ClientError {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        status::Custom(self.http_status(), self.to_string()).respond_to(req)
+    }
+}
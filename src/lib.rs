@@ -3,6 +3,10 @@ extern crate rocket;
 
 mod client;
 mod client_endpoints;
+mod config;
 mod database;
+mod error;
 
-pub use client::*;
\ No newline at end of file
+pub use client::*;
+pub use config::ClientConfig;
+pub use error::{ClientError, ClientResult};
\ No newline at end of file